@@ -1,68 +1,182 @@
 use pdfium_render::prelude::*;
 
+use trombinoscope::cache::ImageCache;
+use trombinoscope::codepage::LegacyEncoding;
+use trombinoscope::decoration::{BandConfig, BandText};
+use trombinoscope::font::FontConfig;
+use trombinoscope::imaging::{CropStrategy, FitMode, Resampling};
+use trombinoscope::label::LabelConfig;
+use trombinoscope::layout::LayoutMode;
+use trombinoscope::person::{Person, HIDDEN_FLAG};
 use trombinoscope::poster;
-use trombinoscope::tools;
+use trombinoscope::source::PictureSource;
+use trombinoscope::validation::ValidationConfig;
 
-use regex::Regex;
+use std::collections::HashMap;
+use std::time::Duration;
 
-use std::fs;
+fn main() -> trombinoscope::Result<()> {
+    // Open cache of processed images (created if not already present)
+    let cache_dir = std::env::var("TROMBINOSCOPE_CACHE").unwrap_or("cache".to_string());
+    let mut cache = ImageCache::open(std::path::Path::new(&cache_dir))?;
 
-fn main() -> () {
-    // Create caching folder, if not already present
-    let cache_dir = std::path::Path::new("cache");
-    fs::create_dir_all(cache_dir).unwrap();
+    // Read archive (or directory) contents
+    let fname = std::env::args()
+        .nth(1)
+        .unwrap_or("PJ illustrés 2024 V5.zip".to_string());
+    let source = PictureSource::from_path(std::path::Path::new(&fname))
+        .with_legacy_encoding(LegacyEncoding::from_env());
+    let mut pictures: Vec<Person> = source.load()?;
+    pictures.retain(|p| !p.has_flag(HIDDEN_FLAG));
 
-    // Read archive contents
-    let fname = std::path::Path::new("PJ illustrés 2024 V5.zip");
-    let mut pictures: Vec<(String, Vec<u8>)> = tools::load_images_from_archive(fname).unwrap();
-    let nb_pics = pictures.len() as i32;
+    // Sort people per prefix & character name (unless the manifest says otherwise)
+    pictures.sort_by(|a, b| a.sort_key.cmp(&b.sort_key));
 
-    // Sort images per prefix & character name
-    pictures.sort_by(|a, b| a.0.cmp(&b.0));
-
-    // Remove prefix and file extension
-    let re = Regex::new(r"^(?:[^_]+)_([^.]+)\..+$").unwrap();
-
-    for entry in pictures.iter_mut() {
-        let caps = re.captures(&entry.0).unwrap();
-        entry.0 = caps[1].to_string();
-    }
+    // Paper size and orientation may be given after the pictures path (defaults to A3 landscape)
+    let paper_size = match std::env::args().nth(2) {
+        Some(name) => match poster::PaperSize::from_name(&name) {
+            Some(paper_size) => paper_size,
+            None => {
+                return Err(trombinoscope::Error::UnknownValue {
+                    setting: "paper size",
+                    value: name,
+                    accepted: poster::PaperSize::NAMES,
+                })
+            }
+        },
+        None => poster::PaperSize::A3,
+    };
+    let orientation = match std::env::args().nth(3) {
+        Some(name) => match poster::PageOrientation::from_name(&name) {
+            Some(orientation) => orientation,
+            None => {
+                return Err(trombinoscope::Error::UnknownValue {
+                    setting: "orientation",
+                    value: name,
+                    accepted: poster::PageOrientation::NAMES,
+                })
+            }
+        },
+        None => poster::PageOrientation::Landscape,
+    };
 
-    // Define a grid size; we specify colums to have some control over ratio
-    let nb_columns: i32 = 19;
-    let mut nb_rows: i32 = nb_pics / nb_columns;
-    if nb_columns * nb_rows < nb_pics {
-        nb_rows += 1;
-    }
-    println!("{nb_pics} pictures to layout in a ({nb_columns} x {nb_rows}) grid");
+    // Then the crop strategy ("top" or "smart")
+    let crop_strategy = match std::env::args().nth(4) {
+        Some(name) => match CropStrategy::from_name(&name) {
+            Some(crop_strategy) => crop_strategy,
+            None => {
+                return Err(trombinoscope::Error::UnknownValue {
+                    setting: "crop strategy",
+                    value: name,
+                    accepted: CropStrategy::NAMES,
+                })
+            }
+        },
+        None => CropStrategy::Top,
+    };
 
     let pdfium = Pdfium::default();
     let base_config = poster::RenderConfig {
+        paper_size,
+        orientation,
         // Easier to express margins in mm
         page_hmargin: PdfPoints::from_mm(10.).value,
         page_vmargin: PdfPoints::from_mm(10.).value,
         inner_hmargin: PdfPoints::from_mm(1.).value,
-        inner_vmargin: PdfPoints::from_mm(5.).value,
+        inner_vmargin: PdfPoints::from_mm(1.5).value, // Labels have a band of their own
         max_dpi: None,
+        fit_mode: FitMode::Fill,
+        fit_background: PdfColor::WHITE,
+        crop_strategy,
+        resampling: Resampling::default(),
+        jpeg_quality: 75,
+        jobs: None,
+        min_photo_width: PdfPoints::from_mm(20.).value,
+        labels: LabelConfig::default(),
+        fonts: FontConfig::from_env(),
+        // Official posters carry the club logos (looked up from the working directory, see
+        // BandConfig::from_env to change them)
+        header: BandConfig::from_env(
+            "HEADER",
+            Some(BandConfig {
+                height: PdfPoints::from_mm(20.).value,
+                left_logo: Some("logos/COPS_logo.png".into()),
+                right_logo: Some("logos/020_Cervall_COPS_logo.png".into()),
+                left: Vec::new(),
+                center: std::iter::once(BandText::new("{title}", 20.))
+                    .chain(
+                        std::env::var("TROMBINOSCOPE_SUBTITLE")
+                            .ok()
+                            .map(|subtitle| BandText::new(&subtitle, 12.)),
+                    )
+                    .collect(),
+                right: Vec::new(),
+            }),
+        ),
+        footer: BandConfig::from_env(
+            "FOOTER",
+            Some(BandConfig {
+                height: PdfPoints::from_mm(5.).value,
+                left_logo: None,
+                right_logo: None,
+                left: vec![BandText::new("Generated on {date}", 6.)],
+                center: Vec::new(),
+                right: vec![BandText::new(
+                    "trombinoscope {version} - page {page}/{pages}",
+                    6.,
+                )],
+            }),
+        ),
+        // Show factions (file name prefixes, or manifest groups)
+        sections: Some(poster::SectionConfig {
+            header_height: PdfPoints::from_mm(5.).value,
+            font_size: 8.,
+            text_color: PdfColor::WHITE,
+            band_color: PdfColor::GREY_40,
+            group_colors: HashMap::new(),
+        }),
+        layout_mode: LayoutMode::Grid,
+        validation: Some(ValidationConfig::default()),
+        debug: false,
     };
 
-    let title = fname.file_stem().unwrap().to_str().unwrap().into();
+    let title = source.title();
 
-    // Generate PDFs at different target DPIs
-    for dpi in [300, 600, 1200, 0] {
-        let config = poster::RenderConfig {
-            max_dpi: if dpi > 0 { Some(dpi) } else { None },
-            ..base_config
-        };
+    // Generate PDFs at different target DPIs, from the same layout
+    let variants: Vec<poster::OutputVariant> = [300, 600, 1200, 0]
+        .iter()
+        .map(|dpi| poster::OutputVariant {
+            filename: if *dpi > 0 {
+                format!("trombinoscope-poster-{dpi}dpi.pdf")
+            } else {
+                "trombinoscope-poster.pdf".to_string()
+            },
+            max_dpi: if *dpi > 0 { Some(*dpi) } else { None },
+            jpeg_quality: base_config.jpeg_quality,
+        })
+        .collect();
 
-        let filename: String = match config.max_dpi {
-            Some(dpi) => format!("trombinoscope-poster-{dpi}dpi.pdf"),
-            None => "trombinoscope-poster.pdf".to_string(),
-        };
+    let reports = poster::generate_variants(
+        &pdfium,
+        &pictures,
+        None,
+        &base_config,
+        &variants,
+        &mut cache,
+        &title,
+    )?;
 
-        poster::generate(
-            &pdfium, &pictures, nb_rows, nb_columns, &config, &filename, cache_dir, &title,
-        )
-        .unwrap();
+    // Keep a detailed report next to each PDF, to pick the one to print
+    for report in reports.iter() {
+        println!("{}", report.summary());
+        report.save(&std::path::Path::new(&report.filename).with_extension("json"))?;
     }
+
+    // Forget images no run has used for a month
+    let pruned = cache.prune(Some(Duration::from_secs(30 * 24 * 3600)), None)?;
+    if pruned > 0 {
+        println!("Removed {pruned} stale images from cache");
+    }
+
+    Ok(())
 }
@@ -0,0 +1,352 @@
+use crate::error::{Error, Result};
+use crate::imaging::Rectangle;
+use crate::tools;
+use crate::validation::Issue;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const INDEX_FILE_NAME: &str = "index.json";
+
+/// Subdirectory of the directory given to [ImageCache::open] actually holding the cache
+const CACHE_DIR_NAME: &str = "trombinoscope-cache";
+
+/// Marks a directory as a cache (see https://bford.info/cachedir/): nothing is ever removed from
+/// a directory without it
+const MARKER_FILE_NAME: &str = "CACHEDIR.TAG";
+const MARKER: &str = "Signature: 8a477f597d28d172789f06886806bc55\n\
+    # This file is a cache directory tag created by trombinoscope.\n";
+
+/// Extension of cached images, named after their key
+const IMAGE_EXTENSION: &str = "jpg";
+
+/// What the cache knows about a processed image
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Name of the picture the image was processed from (for humans only)
+    pub name: String,
+    /// Size of the processed image, in bytes
+    pub size: u64,
+    /// Last time the image was stored or reused, in seconds since the Unix epoch
+    pub last_used: u64,
+    /// Hash of the source bytes (empty when unknown)
+    #[serde(default)]
+    pub source: String,
+}
+
+/// A crop computed for a picture
+#[derive(Clone, Serialize, Deserialize)]
+struct CropEntry {
+    crop: Rectangle,
+    /// Hash of the source bytes: the crop is forgotten along with the last image processed from
+    /// this source
+    source: String,
+}
+
+/// Issues found checking a picture
+#[derive(Clone, Serialize, Deserialize)]
+struct IssuesEntry {
+    issues: Vec<Issue>,
+    /// Hash of the source bytes, as for crops
+    source: String,
+}
+
+/// Content of the index file
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    images: BTreeMap<String, IndexEntry>,
+    /// Crops computed by (costly) strategies, by key
+    #[serde(default)]
+    crops: BTreeMap<String, CropEntry>,
+    /// Results of picture checks, by key
+    #[serde(default)]
+    issues: BTreeMap<String, IssuesEntry>,
+}
+
+/// A cache of processed (cropped, resized, re-encoded) images.
+/// Images are stored under a hash of their source bytes and of every processing parameter,
+/// so replacing a photo or changing how it is processed never reuses a stale image.
+pub struct ImageCache {
+    dir: PathBuf,
+    index: Index,
+}
+
+impl ImageCache {
+    /// Open (or create) a cache in a `trombinoscope-cache` subdirectory of `dir`
+    pub fn open(dir: &Path) -> Result<Self> {
+        let dir = dir.join(CACHE_DIR_NAME);
+        fs::create_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+
+        // Only tag directories created for the cache (which are empty at first)
+        let marker_path = dir.join(MARKER_FILE_NAME);
+        let is_empty = fs::read_dir(&dir)
+            .map_err(|e| Error::io(&dir, e))?
+            .next()
+            .is_none();
+        if is_empty {
+            tools::save_bytes_to_disk(&marker_path, MARKER.as_bytes())?;
+        }
+
+        let index_path = dir.join(INDEX_FILE_NAME);
+        let index = match tools::load_bytes_from_disk(&index_path) {
+            // A corrupted index only costs some reprocessing
+            Some(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                println!("Ignoring invalid cache index {}: {e}", index_path.display());
+                Index::default()
+            }),
+            None => Index::default(),
+        };
+
+        return Ok(ImageCache { dir, index });
+    }
+
+    pub fn dir(&self) -> &Path {
+        return &self.dir;
+    }
+
+    pub fn entries(&self) -> &BTreeMap<String, IndexEntry> {
+        return &self.index.images;
+    }
+
+    /// Compute the key of an image processed from `source` bytes;
+    /// `params` must describe every parameter which affects the processed image
+    pub fn key(source: &[u8], params: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(source);
+        hasher.update([0u8]);
+        hasher.update(params.as_bytes());
+        return hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+    }
+
+    /// Get a cached image, if present
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let bytes = tools::load_bytes_from_disk(&self.path_for(key))?;
+        let entry = self
+            .index
+            .images
+            .entry(key.to_string())
+            .or_insert(IndexEntry {
+                name: String::new(),
+                size: bytes.len() as u64,
+                last_used: 0,
+                source: String::new(),
+            });
+        entry.last_used = now();
+        return Some(bytes);
+    }
+
+    /// Store an image processed from `source` bytes
+    pub fn put(&mut self, key: &str, name: &str, source: &[u8], bytes: &[u8]) -> Result<()> {
+        tools::save_bytes_to_disk(&self.path_for(key), bytes)?;
+        self.index.images.insert(
+            key.to_string(),
+            IndexEntry {
+                name: name.to_string(),
+                size: bytes.len() as u64,
+                last_used: now(),
+                source: ImageCache::key(source, ""),
+            },
+        );
+        return Ok(());
+    }
+
+    /// Get a crop computed for a picture, if known
+    pub fn get_crop(&self, key: &str) -> Option<Rectangle> {
+        return self.index.crops.get(key).map(|entry| entry.crop);
+    }
+
+    /// Remember a crop computed for a picture from its `source` bytes (saved along with the index)
+    pub fn put_crop(&mut self, key: &str, source: &[u8], crop: Rectangle) {
+        let source = ImageCache::key(source, "");
+        self.index
+            .crops
+            .insert(key.to_string(), CropEntry { crop, source });
+    }
+
+    /// Get the issues found checking a picture, if known
+    pub fn get_issues(&self, key: &str) -> Option<Vec<Issue>> {
+        return self.index.issues.get(key).map(|entry| entry.issues.clone());
+    }
+
+    /// Remember the issues found checking a picture from its `source` bytes (saved along with
+    /// the index)
+    pub fn put_issues(&mut self, key: &str, source: &[u8], issues: Vec<Issue>) {
+        let source = ImageCache::key(source, "");
+        self.index
+            .issues
+            .insert(key.to_string(), IssuesEntry { issues, source });
+    }
+
+    /// Persist the index; to be called once done with the cache
+    pub fn save_index(&self) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(&self.index).expect("Index is always serializable");
+        return tools::save_bytes_to_disk(&self.dir.join(INDEX_FILE_NAME), &bytes);
+    }
+
+    /// Remove images not used for `max_age`, then the least recently used ones until the cache
+    /// holds at most `max_size` bytes. Cached images the index does not know about are removed
+    /// too, as well as the crops and issues of sources no image is left for.
+    /// Return the number of removed files (nothing is removed from a directory not tagged as a
+    /// cache).
+    pub fn prune(&mut self, max_age: Option<Duration>, max_size: Option<u64>) -> Result<usize> {
+        if !self.is_tagged() {
+            return Ok(0);
+        }
+        let mut removed = self.remove_orphans()?;
+
+        if let Some(max_age) = max_age {
+            let limit = now().saturating_sub(max_age.as_secs());
+            let expired: Vec<String> = self
+                .index
+                .images
+                .iter()
+                .filter(|(_, e)| e.last_used < limit)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in expired {
+                self.remove(&key)?;
+                removed += 1;
+            }
+        }
+
+        if let Some(max_size) = max_size {
+            let mut by_age: Vec<(String, IndexEntry)> = self
+                .index
+                .images
+                .iter()
+                .map(|(k, e)| (k.clone(), e.clone()))
+                .collect();
+            by_age.sort_by_key(|(_, e)| e.last_used);
+
+            let mut total: u64 = by_age.iter().map(|(_, e)| e.size).sum();
+            for (key, entry) in by_age {
+                if total <= max_size {
+                    break;
+                }
+                self.remove(&key)?;
+                total -= entry.size;
+                removed += 1;
+            }
+        }
+
+        let sources: BTreeSet<&String> = self.index.images.values().map(|e| &e.source).collect();
+        self.index
+            .crops
+            .retain(|_, entry| sources.contains(&entry.source));
+        self.index
+            .issues
+            .retain(|_, entry| sources.contains(&entry.source));
+
+        self.save_index()?;
+        return Ok(removed);
+    }
+
+    /// Remove every cached image (and computed crop or issue)
+    pub fn clear(&mut self) -> Result<usize> {
+        if !self.is_tagged() {
+            return Ok(0);
+        }
+        self.index = Index::default();
+        let removed = self.remove_orphans()?;
+        self.save_index()?;
+        return Ok(removed);
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        return self.dir.join(format!("{key}.{IMAGE_EXTENSION}"));
+    }
+
+    /// Tell if the cache directory bears the cache marker, warning otherwise
+    fn is_tagged(&self) -> bool {
+        let tagged = self.dir.join(MARKER_FILE_NAME).is_file();
+        if !tagged {
+            println!(
+                "Not pruning {}: {MARKER_FILE_NAME} is missing, it may not be a cache",
+                self.dir.display()
+            );
+        }
+        return tagged;
+    }
+
+    fn remove(&mut self, key: &str) -> Result<()> {
+        self.index.images.remove(key);
+        let path = self.path_for(key);
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(path, e)),
+            _ => Ok(()),
+        };
+    }
+
+    /// Remove cached images which are not in the index (e.g. when it was lost); only files named
+    /// like cached images are considered
+    fn remove_orphans(&mut self) -> Result<usize> {
+        let mut removed = 0;
+        let entries = fs::read_dir(&self.dir).map_err(|e| Error::io(&self.dir, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| Error::io(&self.dir, e))?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&format!(".{IMAGE_EXTENSION}")))
+            else {
+                continue;
+            };
+            if is_key(key) && !self.index.images.contains_key(key) {
+                fs::remove_file(&path).map_err(|e| Error::io(&path, e))?;
+                removed += 1;
+            }
+        }
+        return Ok(removed);
+    }
+}
+
+/// Tell if a name is a cache key (a SHA-256 hash, as lowercase hexadecimal)
+fn is_key(name: &str) -> bool {
+    return name.len() == 64 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+}
+
+fn now() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn issues_are_kept_in_the_index() {
+        let dir = std::env::temp_dir().join("trombinoscope-test-issues");
+        let _ = fs::remove_dir_all(&dir);
+        let issues = vec![
+            Issue::LowDpi {
+                dpi: 90,
+                min_dpi: 150,
+            },
+            Issue::TooDark { brightness: 20. },
+        ];
+
+        let mut cache = ImageCache::open(&dir).unwrap();
+        let key = ImageCache::key(b"source", "validation");
+        cache.put_issues(&key, b"source", issues.clone());
+        cache.save_index().unwrap();
+
+        let cache = ImageCache::open(&dir).unwrap();
+        assert_eq!(cache.get_issues(&key), Some(issues));
+        assert_eq!(
+            cache.get_issues(&ImageCache::key(b"other", "validation")),
+            None
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
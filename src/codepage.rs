@@ -0,0 +1,146 @@
+use encoding::label::{encoding_from_whatwg_label, encoding_from_windows_code_page};
+use encoding::{DecoderTrap, EncodingRef};
+
+/// Upper half (0x80-0xFF) of the original IBM PC code page
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{00A0}",
+);
+
+/// Upper half (0x80-0xFF) of the western European DOS code page (used by French Windows)
+const CP850_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜø£Ø×ƒ",
+    "áíóúñÑªº¿®¬½¼¡«»",
+    "░▒▓│┤ÁÂÀ©╣║╗╝¢¥┐",
+    "└┴┬├─┼ãÃ╚╔╩╦╠═╬¤",
+    "ðÐÊËÈıÍÎÏ┘┌█▄¦Ì▀",
+    "ÓßÔÒõÕµþÞÚÛÙýÝ¯´",
+    "\u{00AD}±‗¾¶§÷¸°¨·¹³²■\u{00A0}",
+);
+
+/// Encoding used to decode archive entry names which are not flagged as UTF-8
+#[derive(Clone, Copy)]
+pub enum LegacyEncoding {
+    /// IBM PC code page, the zip specification default
+    Cp437,
+    /// Western European DOS code page, written by Windows Explorer on French systems
+    Cp850,
+    /// Any other encoding supported by the `encoding` crate
+    Other(EncodingRef),
+}
+
+impl Default for LegacyEncoding {
+    fn default() -> Self {
+        return LegacyEncoding::Cp437;
+    }
+}
+
+impl LegacyEncoding {
+    /// Look up an encoding from its Windows code page number (437, 850, 1252, 932...)
+    pub fn from_code_page(code_page: usize) -> Option<Self> {
+        return match code_page {
+            437 => Some(LegacyEncoding::Cp437),
+            850 => Some(LegacyEncoding::Cp850),
+            _ => encoding_from_windows_code_page(code_page).map(LegacyEncoding::Other),
+        };
+    }
+
+    /// Look up an encoding from its name ("cp850", "windows-1252", "shift_jis"...)
+    pub fn from_label(label: &str) -> Option<Self> {
+        return match label.to_ascii_lowercase().as_str() {
+            "cp437" | "ibm437" | "437" => Some(LegacyEncoding::Cp437),
+            "cp850" | "ibm850" | "850" => Some(LegacyEncoding::Cp850),
+            other => encoding_from_whatwg_label(other).map(LegacyEncoding::Other),
+        };
+    }
+
+    /// Encoding set by `TROMBINOSCOPE_LEGACY_ENCODING`, as a name or a code page number
+    /// (defaults to CP437)
+    pub fn from_env() -> Self {
+        let Ok(name) = std::env::var("TROMBINOSCOPE_LEGACY_ENCODING") else {
+            return LegacyEncoding::default();
+        };
+        let encoding = match name.parse::<usize>() {
+            Ok(code_page) => LegacyEncoding::from_code_page(code_page),
+            Err(_) => LegacyEncoding::from_label(&name),
+        };
+        return match encoding {
+            Some(encoding) => encoding,
+            None => {
+                println!("Ignoring unknown legacy encoding {name}");
+                LegacyEncoding::default()
+            }
+        };
+    }
+
+    /// Decode an archive entry name which is not flagged as UTF-8. Some archivers (on macOS
+    /// notably) write UTF-8 names without the flag: names which are valid UTF-8 are kept as is.
+    pub fn decode_name(&self, raw: &[u8]) -> String {
+        return match std::str::from_utf8(raw) {
+            Ok(name) => name.to_string(),
+            Err(_) => self.decode(raw),
+        };
+    }
+
+    /// Decode raw bytes; undecodable sequences are replaced rather than rejected
+    pub fn decode(&self, raw: &[u8]) -> String {
+        let high_half = match self {
+            LegacyEncoding::Cp437 => CP437_HIGH,
+            LegacyEncoding::Cp850 => CP850_HIGH,
+            LegacyEncoding::Other(encoding) => {
+                return encoding
+                    .decode(raw, DecoderTrap::Replace)
+                    .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned());
+            }
+        };
+
+        return raw
+            .iter()
+            .map(|&b| match b {
+                0x00..=0x7F => b as char,
+                _ => high_half.chars().nth((b - 0x80) as usize).unwrap(),
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "Hélène Bèze - MÈRE.jpg" as written by Windows Explorer on a French system
+    const CP850_NAME: &[u8] = b"H\x82l\x8Ane B\x8Aze - M\xD4RE.jpg";
+
+    #[test]
+    fn decodes_cp850_accents() {
+        let encoding = LegacyEncoding::from_label("cp850").unwrap();
+        assert_eq!(encoding.decode(CP850_NAME), "Hélène Bèze - MÈRE.jpg");
+    }
+
+    #[test]
+    fn unflagged_utf8_names_are_kept() {
+        let name = "B_Hélène.jpg";
+        assert_eq!(LegacyEncoding::Cp437.decode_name(name.as_bytes()), name);
+        assert_eq!(
+            LegacyEncoding::Cp850.decode_name(CP850_NAME),
+            "Hélène Bèze - MÈRE.jpg"
+        );
+    }
+
+    #[test]
+    fn cp437_differs_for_upper_case_accents() {
+        assert_eq!(
+            LegacyEncoding::from_code_page(437)
+                .unwrap()
+                .decode(CP850_NAME),
+            "Hélène Bèze - M╘RE.jpg"
+        );
+    }
+}
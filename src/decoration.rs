@@ -0,0 +1,365 @@
+use crate::error::Result;
+use crate::font::{FontRole, LoadedFonts, TextStyle};
+use crate::label;
+use crate::layout::Area;
+use crate::tools;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use pdfium_render::prelude::*;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Logos are scaled down to this resolution (small on the page, but printed sharp)
+const LOGO_DPI: f32 = 600.;
+
+/// Distance between logos and texts, relative to the band height
+const LOGO_GAP: f32 = 0.25;
+
+/// Band texts too wide for their column may shrink down to this share of their size before
+/// being cut
+const MIN_FONT_SCALE: f32 = 0.7;
+
+/// Size of band texts given without one (in `TROMBINOSCOPE_*` variables)
+const DEFAULT_FONT_SIZE: f32 = 10.;
+
+/// A band at the top (header) or bottom (footer) of each page, within the page margins.
+/// The grid shrinks to leave room for it.
+#[derive(Clone, Debug)]
+pub struct BandConfig {
+    pub height: f32,
+    /// Images drawn at the ends of the band, scaled to its height
+    pub left_logo: Option<PathBuf>,
+    pub right_logo: Option<PathBuf>,
+    /// Lines of text (top to bottom) next to the left logo, centred, and next to the right logo
+    pub left: Vec<BandText>,
+    pub center: Vec<BandText>,
+    pub right: Vec<BandText>,
+}
+
+/// A line of text in a band. `{title}`, `{date}` (of generation), `{version}` (of this program),
+/// `{page}` and `{pages}` are replaced by their value.
+#[derive(Clone, Debug)]
+pub struct BandText {
+    pub text: String,
+    pub font_size: f32,
+    pub color: PdfColor,
+}
+
+impl BandText {
+    pub fn new(text: &str, font_size: f32) -> Self {
+        return BandText {
+            text: text.to_string(),
+            font_size,
+            color: PdfColor::BLACK,
+        };
+    }
+
+    /// Read a line given as `text` or `font_size:text`
+    pub fn parse(spec: &str) -> Self {
+        if let Some((size, text)) = spec.split_once(':') {
+            if let Ok(font_size) = size.trim().parse::<f32>() {
+                return BandText::new(text, font_size);
+            }
+        }
+        return BandText::new(spec, DEFAULT_FONT_SIZE);
+    }
+
+    fn expand(&self, values: &BandValues) -> String {
+        return self
+            .text
+            .replace("{title}", values.title)
+            .replace("{date}", values.date)
+            .replace("{version}", env!("CARGO_PKG_VERSION"))
+            .replace("{page}", &values.page.to_string())
+            .replace("{pages}", &values.pages.to_string());
+    }
+}
+
+impl Default for BandConfig {
+    fn default() -> Self {
+        return BandConfig {
+            height: PdfPoints::from_mm(10.).value,
+            left_logo: None,
+            right_logo: None,
+            left: Vec::new(),
+            center: Vec::new(),
+            right: Vec::new(),
+        };
+    }
+}
+
+impl BandConfig {
+    /// Override the `default` band (`band` being "HEADER" or "FOOTER") with environment variables:
+    /// - `TROMBINOSCOPE_<band>=none` removes the band
+    /// - `TROMBINOSCOPE_<band>_HEIGHT`, in mm
+    /// - `TROMBINOSCOPE_<band>_LEFT_LOGO` and `_RIGHT_LOGO`, image paths (empty for no logo)
+    /// - `TROMBINOSCOPE_<band>_LEFT`, `_CENTER` and `_RIGHT`, lines of text separated by `|`,
+    ///   each one optionally prefixed by its font size (`20:{title}|12:Season 2`)
+    ///
+    /// Without a `default`, a band is only added when one of these variables is set.
+    pub fn from_env(band: &str, default: Option<BandConfig>) -> Option<Self> {
+        let var = |name: &str| std::env::var(format!("TROMBINOSCOPE_{band}{name}")).ok();
+        if var("").is_some_and(|value| value.eq_ignore_ascii_case("none")) {
+            return None;
+        }
+
+        let overridden = [
+            "_HEIGHT",
+            "_LEFT_LOGO",
+            "_RIGHT_LOGO",
+            "_LEFT",
+            "_CENTER",
+            "_RIGHT",
+        ]
+        .iter()
+        .any(|name| var(name).is_some());
+        let mut config = match default {
+            Some(config) => config,
+            None if overridden => BandConfig::default(),
+            None => return None,
+        };
+
+        if let Some(height) = var("_HEIGHT") {
+            match height.parse::<f32>() {
+                Ok(height) => config.height = PdfPoints::from_mm(height).value,
+                Err(_) => println!("Ignoring invalid {band} height {height}"),
+            }
+        }
+        let logo = |value: String| (!value.is_empty()).then(|| PathBuf::from(value));
+        if let Some(path) = var("_LEFT_LOGO") {
+            config.left_logo = logo(path);
+        }
+        if let Some(path) = var("_RIGHT_LOGO") {
+            config.right_logo = logo(path);
+        }
+        let lines = |value: String| value.split('|').map(BandText::parse).collect();
+        if let Some(value) = var("_LEFT") {
+            config.left = lines(value);
+        }
+        if let Some(value) = var("_CENTER") {
+            config.center = lines(value);
+        }
+        if let Some(value) = var("_RIGHT") {
+            config.right = lines(value);
+        }
+        return Some(config);
+    }
+
+    /// Lines of text of the band, with placeholders replaced by their value
+    pub fn texts(&self, values: &BandValues) -> Vec<String> {
+        return [&self.left, &self.center, &self.right]
+            .into_iter()
+            .flatten()
+            .map(|line| tools::normalize_unicode(&line.expand(values)))
+            .collect();
+    }
+}
+
+/// What placeholders of a page band texts stand for
+pub struct BandValues<'a> {
+    pub title: &'a str,
+    pub date: &'a str,
+    /// Page number, from 1
+    pub page: usize,
+    pub pages: usize,
+}
+
+/// A band, with its logos loaded
+pub struct Band<'c> {
+    config: &'c BandConfig,
+    left_logo: Option<DynamicImage>,
+    right_logo: Option<DynamicImage>,
+}
+
+impl<'c> Band<'c> {
+    /// Load (and scale down) the band logos, once for all pages. Logos which cannot be loaded are
+    /// left out, with a warning.
+    pub fn load(config: &'c BandConfig, warnings: &mut Vec<String>) -> Self {
+        let mut load_logo = |path: &Option<PathBuf>| -> Option<DynamicImage> {
+            let path = path.as_ref()?;
+            let logo = match tools::load_image_from_disk(path) {
+                Ok(logo) => logo,
+                Err(e) => {
+                    warnings.push(format!("Logo left out: {e}"));
+                    return None;
+                }
+            };
+            let height = (PdfPoints::new(config.height).to_inches() * LOGO_DPI).ceil() as u32;
+            if logo.height() <= height {
+                return Some(logo);
+            }
+            let width = (logo.width() as f32 * height as f32 / logo.height() as f32).ceil();
+            return Some(logo.resize(width as u32, height, FilterType::Lanczos3));
+        };
+
+        let left_logo = load_logo(&config.left_logo);
+        let right_logo = load_logo(&config.right_logo);
+        return Band {
+            config,
+            left_logo,
+            right_logo,
+        };
+    }
+
+    /// Draw the band in `area` of a page
+    pub fn draw<'a>(
+        &self,
+        document: &PdfDocument<'a>,
+        page: &mut PdfPage<'a>,
+        fonts: &LoadedFonts,
+        area: &Area,
+        values: &BandValues,
+    ) -> Result<()> {
+        let gap = area.height * LOGO_GAP;
+        let mut text_left = area.left;
+        let mut text_right = area.right();
+
+        if let Some(logo) = &self.left_logo {
+            draw_logo(document, page, logo, area.left, area)?;
+            text_left += logo_width(logo, area) + gap;
+        }
+        if let Some(logo) = &self.right_logo {
+            draw_logo(
+                document,
+                page,
+                logo,
+                area.right() - logo_width(logo, area),
+                area,
+            )?;
+            text_right -= logo_width(logo, area) + gap;
+        }
+
+        // Side columns get a share of the width between logos, the centred one what the widest
+        // side column leaves on both sides
+        let config = self.config;
+        let text_width = (text_right - text_left).max(0.);
+        let side_width = if !config.center.is_empty() {
+            text_width / 3.
+        } else if !config.left.is_empty() && !config.right.is_empty() {
+            (text_width - gap) / 2.
+        } else {
+            text_width
+        };
+        let left = self.layout_column(document, fonts, &config.left, values, side_width)?;
+        let right = self.layout_column(document, fonts, &config.right, values, side_width)?;
+        let widest_side = left
+            .iter()
+            .chain(&right)
+            .map(|(_, width)| *width)
+            .fold(0., f32::max);
+        let center_width = if widest_side > 0. {
+            text_width - 2. * (widest_side + gap)
+        } else {
+            text_width
+        };
+        let center = self.layout_column(document, fonts, &config.center, values, center_width)?;
+
+        // Columns are vertically centred in the band
+        let columns = [
+            (&config.left, left, Alignment::Left),
+            (&config.center, center, Alignment::Center),
+            (&config.right, right, Alignment::Right),
+        ];
+        for (lines, laid_out, alignment) in columns {
+            let block_height: f32 = lines.iter().map(|l| l.font_size * 1.2).sum();
+            let mut top = area.bottom + (area.height + block_height) / 2.;
+            for (line, (layout, width)) in lines.iter().zip(laid_out) {
+                let style = TextStyle {
+                    role: FontRole::Title,
+                    font_size: layout.font_size,
+                    color: line.color,
+                };
+                let left = match alignment {
+                    Alignment::Left => text_left,
+                    Alignment::Center => text_left + (text_width - width) / 2.,
+                    Alignment::Right => text_right - width,
+                };
+                top -= line.font_size * 1.2;
+                fonts.draw(
+                    document,
+                    page,
+                    &layout.lines[0],
+                    &style,
+                    left,
+                    top + line.font_size * 0.25,
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Fit the lines of a column in `max_width` (shrunk, then cut), with their width
+    fn layout_column(
+        &self,
+        document: &PdfDocument,
+        fonts: &LoadedFonts,
+        lines: &[BandText],
+        values: &BandValues,
+        max_width: f32,
+    ) -> Result<Vec<(label::LabelLayout, f32)>> {
+        let measure = |text: &str, font_size: f32| -> Result<f32> {
+            return fonts.measure(document, FontRole::Title, text, font_size);
+        };
+        let mut laid_out = Vec::new();
+        for line in lines {
+            let text = tools::normalize_unicode(&line.expand(values));
+            let layout = label::layout_line(
+                &text,
+                max_width.max(0.),
+                line.font_size,
+                line.font_size * MIN_FONT_SCALE,
+                measure,
+            )?;
+            let width = measure(&layout.lines[0], layout.font_size)?;
+            laid_out.push((layout, width));
+        }
+        return Ok(laid_out);
+    }
+}
+
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+/// Width of a logo scaled to the height of a band
+fn logo_width(logo: &DynamicImage, area: &Area) -> f32 {
+    return area.height * logo.width() as f32 / logo.height() as f32;
+}
+
+/// Draw a logo in a band, from `left`, scaled to the band height
+fn draw_logo<'a>(
+    document: &PdfDocument<'a>,
+    page: &mut PdfPage<'a>,
+    logo: &DynamicImage,
+    left: f32,
+    area: &Area,
+) -> Result<()> {
+    let mut image_object =
+        PdfPageImageObject::new_with_height(document, logo, PdfPoints::new(area.height))?;
+    image_object.translate(PdfPoints::new(left), PdfPoints::new(area.bottom))?;
+    page.objects_mut().add_image_object(image_object)?;
+    return Ok(());
+}
+
+/// Today's date (UTC), as YYYY-MM-DD
+pub fn generation_date() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0) as i64;
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!("{year:04}-{month:02}-{day:02}");
+}
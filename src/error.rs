@@ -0,0 +1,158 @@
+use pdfium_render::prelude::PdfiumError;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors which can occur while loading pictures or generating a trombinoscope
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be read or written
+    Io { path: PathBuf, source: io::Error },
+    /// The pictures archive could not be parsed
+    Zip(zip::result::ZipError),
+    /// An archive entry is shorter than its declared size (truncated or corrupted archive)
+    TruncatedEntry { name: String, size: u64, read: u64 },
+    /// The manifest describing people could not be parsed
+    Manifest {
+        name: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The header of a picture could not be parsed (unknown format or corrupted data)
+    ImageSize {
+        name: String,
+        source: imagesize::ImageError,
+    },
+    /// A picture could not be decoded
+    Decoding {
+        name: String,
+        source: image::ImageError,
+    },
+    /// A picture could not be re-encoded
+    Encoding {
+        name: String,
+        source: image::ImageError,
+    },
+    /// A font could not be used
+    Font { name: String, reason: &'static str },
+    /// Some labels have characters no font can draw (label and missing characters)
+    MissingGlyphs(Vec<(String, String)>),
+    /// A setting has a value which is not one of those accepted
+    UnknownValue {
+        setting: &'static str,
+        value: String,
+        accepted: &'static [&'static str],
+    },
+    /// Margins, bands and labels leave no room for pictures (size of the frame, in points)
+    NoRoomForGrid { width: f32, height: f32 },
+    /// The grid has too many rows or columns for its cells to hold a picture (cell size, in points)
+    EmptyCells {
+        rows: i32,
+        columns: i32,
+        width: f32,
+        height: f32,
+    },
+    /// The PDF document could not be built or saved
+    Pdf(PdfiumError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Build an I/O error tied to the path which caused it
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        return Error::Io {
+            path: path.into(),
+            source,
+        };
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "I/O error with {}: {source}", path.display()),
+            Error::Zip(e) => write!(f, "Invalid archive: {e}"),
+            Error::TruncatedEntry { name, size, read } => write!(
+                f,
+                "Archive entry {name} is truncated ({read} bytes read out of {size})"
+            ),
+            Error::Manifest { name, source } => write!(f, "Invalid manifest {name}: {source}"),
+            Error::ImageSize { name, source } => {
+                write!(f, "Could not guess image size of {name}: {source}")
+            }
+            Error::Decoding { name, source } => {
+                write!(f, "An error occured when decoding {name}: {source}")
+            }
+            Error::Encoding { name, source } => {
+                write!(f, "An error occured when encoding {name} to JPEG: {source}")
+            }
+            Error::Font { name, reason } => write!(f, "Unusable font {name}: {reason}"),
+            Error::NoRoomForGrid { width, height } => write!(
+                f,
+                "Margins, bands and labels leave no room for pictures ({width:.1} x {height:.1} pt frames)"
+            ),
+            Error::EmptyCells {
+                rows,
+                columns,
+                width,
+                height,
+            } => write!(
+                f,
+                "A grid of {rows} rows and {columns} columns leaves no room for pictures (cells of {width:.1} x {height:.1} pt)"
+            ),
+            Error::UnknownValue {
+                setting,
+                value,
+                accepted,
+            } => write!(
+                f,
+                "Unknown {setting} \"{value}\" (accepted: {})",
+                accepted.join(", ")
+            ),
+            Error::MissingGlyphs(labels) => {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(label, missing)| format!("{label} ({missing})"))
+                    .collect();
+                write!(
+                    f,
+                    "No font can draw some characters of: {}",
+                    labels.join(", ")
+                )
+            }
+            Error::Pdf(e) => write!(f, "PDF error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Zip(e) => Some(e),
+            Error::TruncatedEntry { .. } => None,
+            Error::Manifest { source, .. } => Some(source.as_ref()),
+            Error::ImageSize { source, .. } => Some(source),
+            Error::Decoding { source, .. } => Some(source),
+            Error::Encoding { source, .. } => Some(source),
+            Error::Font { .. }
+            | Error::MissingGlyphs(_)
+            | Error::UnknownValue { .. }
+            | Error::NoRoomForGrid { .. }
+            | Error::EmptyCells { .. } => None,
+            Error::Pdf(e) => Some(e),
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        return Error::Zip(e);
+    }
+}
+
+impl From<PdfiumError> for Error {
+    fn from(e: PdfiumError) -> Self {
+        return Error::Pdf(e);
+    }
+}
@@ -0,0 +1,551 @@
+use crate::error::{Error, Result};
+use encoding::all::WINDOWS_1252;
+use encoding::{EncoderTrap, Encoding};
+use pdfium_render::prelude::*;
+use std::borrow::Cow;
+use std::fs;
+use std::path::PathBuf;
+
+/// The default font, shipped with the program
+const CHANDLER42: &[u8] = include_bytes!("../font/Chandler42 Regular.otf");
+
+/// Where a font comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum FontSource {
+    /// Chandler42, bundled with the program
+    Chandler42,
+    /// One of the standard PDF fonts (not embedded, Western European characters only)
+    Standard(PdfFontBuiltin),
+    /// A TrueType or OpenType font file
+    File(PathBuf),
+}
+
+impl FontSource {
+    /// Look up a bundled font from its name ("chandler42", "helvetica", "times"...),
+    /// anything else being a path to a font file
+    pub fn from_name(name: &str) -> Self {
+        return match name.to_lowercase().as_str() {
+            "chandler42" => FontSource::Chandler42,
+            "helvetica" => FontSource::Standard(PdfFontBuiltin::Helvetica),
+            "helvetica-bold" => FontSource::Standard(PdfFontBuiltin::HelveticaBold),
+            "times" => FontSource::Standard(PdfFontBuiltin::TimesRoman),
+            "times-bold" => FontSource::Standard(PdfFontBuiltin::TimesBold),
+            "courier" => FontSource::Standard(PdfFontBuiltin::Courier),
+            _ => FontSource::File(PathBuf::from(name)),
+        };
+    }
+
+    /// A human readable name for this font
+    pub fn name(&self) -> String {
+        return match self {
+            FontSource::Chandler42 => "Chandler42".to_string(),
+            FontSource::Standard(builtin) => format!("{builtin:?}"),
+            FontSource::File(path) => path.display().to_string(),
+        };
+    }
+
+    fn read(&self) -> Result<Font> {
+        let bytes: Cow<'static, [u8]> = match self {
+            FontSource::Chandler42 => Cow::Borrowed(CHANDLER42),
+            FontSource::Standard(_) => {
+                return Ok(Font {
+                    source: self.clone(),
+                    bytes: None,
+                    coverage: Coverage::WinAnsi,
+                })
+            }
+            FontSource::File(path) => Cow::Owned(fs::read(path).map_err(|e| Error::io(path, e))?),
+        };
+
+        let char_map = CharMap::parse(&bytes).ok_or_else(|| Error::Font {
+            name: self.name(),
+            reason: "no readable Unicode character map",
+        })?;
+        return Ok(Font {
+            source: self.clone(),
+            bytes: Some(bytes),
+            coverage: Coverage::CharMap(char_map),
+        });
+    }
+}
+
+/// Fonts used to draw the poster texts
+#[derive(Clone, Debug)]
+pub struct FontConfig {
+    pub label: FontSource,
+    pub subtitle: FontSource,
+    /// Poster title and section headers
+    pub title: FontSource,
+    /// Fonts drawing the characters the above ones miss, by order of preference
+    pub fallbacks: Vec<FontSource>,
+    /// What to do with labels some characters of which no font can draw
+    pub missing_glyphs: MissingGlyphs,
+}
+
+/// How to deal with characters which would be drawn as blank boxes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissingGlyphs {
+    Ignore,
+    /// Report them, but still generate the poster
+    Warn,
+    /// Fail before generating anything
+    Refuse,
+}
+
+impl MissingGlyphs {
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_lowercase().as_str() {
+            "ignore" => Some(MissingGlyphs::Ignore),
+            "warn" => Some(MissingGlyphs::Warn),
+            "refuse" => Some(MissingGlyphs::Refuse),
+            _ => None,
+        };
+    }
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        return FontConfig {
+            label: FontSource::Chandler42,
+            subtitle: FontSource::Chandler42,
+            title: FontSource::Chandler42,
+            fallbacks: vec![FontSource::Standard(PdfFontBuiltin::Helvetica)],
+            missing_glyphs: MissingGlyphs::Warn,
+        };
+    }
+}
+
+impl FontConfig {
+    /// Override the default fonts from the `TROMBINOSCOPE_LABEL_FONT`, `TROMBINOSCOPE_SUBTITLE_FONT`
+    /// and `TROMBINOSCOPE_TITLE_FONT` environment variables (see [FontSource::from_name]),
+    /// and `TROMBINOSCOPE_FALLBACK_FONTS` (a list separated like the `PATH` variable).
+    /// `TROMBINOSCOPE_MISSING_GLYPHS` ("ignore", "warn" or "refuse") sets how missing glyphs are
+    /// dealt with.
+    pub fn from_env() -> Self {
+        let mut config = FontConfig::default();
+        let font = |var: &str| {
+            std::env::var(var)
+                .ok()
+                .map(|name| FontSource::from_name(&name))
+        };
+        if let Some(source) = font("TROMBINOSCOPE_LABEL_FONT") {
+            config.label = source;
+        }
+        if let Some(source) = font("TROMBINOSCOPE_SUBTITLE_FONT") {
+            config.subtitle = source;
+        }
+        if let Some(source) = font("TROMBINOSCOPE_TITLE_FONT") {
+            config.title = source;
+        }
+        if let Some(fallbacks) = std::env::var_os("TROMBINOSCOPE_FALLBACK_FONTS") {
+            config.fallbacks = std::env::split_paths(&fallbacks)
+                .map(|name| FontSource::from_name(&name.to_string_lossy()))
+                .collect();
+        }
+        if let Ok(name) = std::env::var("TROMBINOSCOPE_MISSING_GLYPHS") {
+            match MissingGlyphs::from_name(&name) {
+                Some(missing_glyphs) => config.missing_glyphs = missing_glyphs,
+                None => println!("Ignoring unknown missing glyphs policy {name}"),
+            }
+        }
+        return config;
+    }
+}
+
+/// What a text is drawn for, each role having its own font
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FontRole {
+    Label,
+    Subtitle,
+    Title,
+}
+
+/// Characters a font can draw
+#[derive(Clone, Debug)]
+enum Coverage {
+    CharMap(CharMap),
+    /// Standard PDF fonts use the Windows Latin 1 encoding
+    WinAnsi,
+}
+
+struct Font {
+    source: FontSource,
+    bytes: Option<Cow<'static, [u8]>>,
+    coverage: Coverage,
+}
+
+impl Font {
+    fn covers(&self, c: char) -> bool {
+        return match &self.coverage {
+            Coverage::CharMap(char_map) => char_map.contains(c),
+            Coverage::WinAnsi => {
+                !c.is_control()
+                    && WINDOWS_1252
+                        .encode(&c.to_string(), EncoderTrap::Strict)
+                        .is_ok()
+            }
+        };
+    }
+}
+
+/// The fonts of a [FontConfig], read once and shared by all documents
+pub struct Fonts {
+    fonts: Vec<Font>,
+    /// Indexes in `fonts` of the label, subtitle and title fonts, each followed by the fallbacks
+    chains: [Vec<usize>; 3],
+}
+
+impl Fonts {
+    /// Read (and check) all configured fonts
+    pub fn read(config: &FontConfig) -> Result<Self> {
+        let mut fonts: Vec<Font> = Vec::new();
+        let mut index_of = |source: &FontSource| -> Result<usize> {
+            if let Some(i) = fonts.iter().position(|f| f.source == *source) {
+                return Ok(i);
+            }
+            fonts.push(source.read()?);
+            return Ok(fonts.len() - 1);
+        };
+
+        let mut chains: [Vec<usize>; 3] = Default::default();
+        for (chain, primary) in
+            chains
+                .iter_mut()
+                .zip([&config.label, &config.subtitle, &config.title])
+        {
+            chain.push(index_of(primary)?);
+            for fallback in config.fallbacks.iter() {
+                let i = index_of(fallback)?;
+                if !chain.contains(&i) {
+                    chain.push(i);
+                }
+            }
+        }
+
+        return Ok(Fonts { fonts, chains });
+    }
+
+    /// Tell whether some characters of `text` are missing from the font of `role`, but drawn by
+    /// one of the fallbacks
+    pub fn needs_fallback(&self, role: FontRole, text: &str) -> bool {
+        let chain = self.chain(role);
+        return text.chars().any(|c| {
+            !c.is_whitespace()
+                && !self.fonts[chain[0]].covers(c)
+                && chain[1..].iter().any(|i| self.fonts[*i].covers(c))
+        });
+    }
+
+    /// Get the characters of `text` which no font of the `role` chain can draw (once each)
+    pub fn missing_glyphs(&self, role: FontRole, text: &str) -> Vec<char> {
+        let mut missing = Vec::new();
+        for c in text.chars() {
+            let drawn =
+                c.is_whitespace() || self.chain(role).iter().any(|i| self.fonts[*i].covers(c));
+            if !drawn && !missing.contains(&c) {
+                missing.push(c);
+            }
+        }
+        return missing;
+    }
+
+    /// Add the fonts to a document
+    pub fn load<'f>(&'f self, document: &mut PdfDocument) -> Result<LoadedFonts<'f>> {
+        let mut tokens = Vec::new();
+        for font in self.fonts.iter() {
+            let token = match (&font.source, &font.bytes) {
+                (FontSource::Standard(builtin), _) => document.fonts_mut().new_built_in(*builtin),
+                (_, Some(bytes)) => document
+                    .fonts_mut()
+                    .load_true_type_from_bytes(bytes, true)?,
+                (_, None) => unreachable!("Only standard fonts come without data"),
+            };
+            tokens.push(token);
+        }
+        return Ok(LoadedFonts {
+            fonts: self,
+            tokens,
+        });
+    }
+
+    fn chain(&self, role: FontRole) -> &[usize] {
+        return &self.chains[role as usize];
+    }
+
+    /// Split a text in runs of characters drawn with the same font (by index in `fonts`):
+    /// the first font of the chain which has a glyph for them (or the primary font if none has)
+    fn runs(&self, role: FontRole, text: &str) -> Vec<(usize, String)> {
+        let chain = self.chain(role);
+        let mut runs: Vec<(usize, String)> = Vec::new();
+        for c in text.chars() {
+            // Spaces stay with the text they follow
+            let font = match runs.last() {
+                Some((last, _)) if c.is_whitespace() && self.fonts[*last].covers(c) => *last,
+                _ => chain
+                    .iter()
+                    .copied()
+                    .find(|i| self.fonts[*i].covers(c))
+                    .unwrap_or(chain[0]),
+            };
+            match runs.last_mut() {
+                Some((last, run)) if *last == font => run.push(c),
+                _ => runs.push((font, c.to_string())),
+            }
+        }
+        return runs;
+    }
+}
+
+/// How a text is drawn
+pub struct TextStyle {
+    pub role: FontRole,
+    pub font_size: f32,
+    pub color: PdfColor,
+}
+
+/// [Fonts] added to a document
+pub struct LoadedFonts<'f> {
+    fonts: &'f Fonts,
+    tokens: Vec<PdfFontToken>,
+}
+
+impl LoadedFonts<'_> {
+    /// Get the width of a text, as drawn by [LoadedFonts::draw]
+    pub fn measure(
+        &self,
+        document: &PdfDocument,
+        role: FontRole,
+        text: &str,
+        font_size: f32,
+    ) -> Result<f32> {
+        let mut width = 0.;
+        for (font, run) in self.fonts.runs(role, text) {
+            width += self.run_width(document, font, &run, font_size)?;
+        }
+        return Ok(width);
+    }
+
+    /// Draw a text from its baseline left end, each character with the first font of the chain
+    /// which has a glyph for it
+    pub fn draw<'a>(
+        &self,
+        document: &PdfDocument<'a>,
+        page: &mut PdfPage<'a>,
+        text: &str,
+        style: &TextStyle,
+        left: f32,
+        baseline: f32,
+    ) -> Result<()> {
+        let mut left = left;
+        for (font, run) in self.fonts.runs(style.role, text) {
+            let mut text_object = PdfPageTextObject::new(
+                document,
+                &run,
+                self.tokens[font],
+                PdfPoints::new(style.font_size),
+            )?;
+            text_object.set_fill_color(style.color)?;
+            text_object.translate(PdfPoints::new(left), PdfPoints::new(baseline))?;
+            left += self.run_width(document, font, &run, style.font_size)?;
+
+            // Add the object to the page, triggering content regeneration.
+            page.objects_mut().add_text_object(text_object)?;
+        }
+        return Ok(());
+    }
+
+    fn run_width(
+        &self,
+        document: &PdfDocument,
+        font: usize,
+        run: &str,
+        font_size: f32,
+    ) -> Result<f32> {
+        let text_object =
+            PdfPageTextObject::new(document, run, self.tokens[font], PdfPoints::new(font_size))?;
+        let bounds = text_object.bounds()?;
+        return Ok(bounds.x3.value - bounds.x1.value);
+    }
+}
+
+/// Characters a font has glyphs for, as sorted and disjoint ranges of code points
+#[derive(Clone, Debug, Default)]
+pub struct CharMap {
+    ranges: Vec<(u32, u32)>,
+}
+
+impl CharMap {
+    /// Read the Unicode subtables (formats 4 and 12) of the `cmap` table of a TrueType or
+    /// OpenType font (the first one of a collection)
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let cmap = find_table(data, b"cmap")?;
+        let mut ranges = Vec::new();
+        let mut found = false;
+        for i in 0..read_u16(cmap, 2)? as usize {
+            let record = 4 + i * 8;
+            let platform = read_u16(cmap, record)?;
+            let encoding = read_u16(cmap, record + 2)?;
+            if !(platform == 0 || platform == 3 && (encoding == 1 || encoding == 10)) {
+                continue;
+            }
+            let subtable = cmap.get(read_u32(cmap, record + 4)? as usize..)?;
+            match read_u16(subtable, 0)? {
+                4 => parse_format4(subtable, &mut ranges)?,
+                12 => parse_format12(subtable, &mut ranges)?,
+                _ => continue,
+            }
+            found = true;
+        }
+        if !found {
+            return None;
+        }
+
+        ranges.sort();
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        return Some(CharMap { ranges: merged });
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        let c = c as u32;
+        let i = self.ranges.partition_point(|(_, end)| *end < c);
+        return self.ranges.get(i).is_some_and(|(start, _)| *start <= c);
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    return Some(u16::from_be_bytes([bytes[0], bytes[1]]));
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    return Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+}
+
+/// Find a table in the font directory
+fn find_table<'d>(data: &'d [u8], tag: &[u8; 4]) -> Option<&'d [u8]> {
+    // Collections start with their own header, pointing to the directory of each font
+    let directory = if data.starts_with(b"ttcf") {
+        read_u32(data, 12)? as usize
+    } else {
+        0
+    };
+    for i in 0..read_u16(data, directory + 4)? as usize {
+        let record = directory + 12 + i * 16;
+        if data.get(record..record + 4)? == tag {
+            let offset = read_u32(data, record + 8)? as usize;
+            let length = read_u32(data, record + 12)? as usize;
+            return data.get(offset..offset + length);
+        }
+    }
+    return None;
+}
+
+/// Segment mapping to delta values (Basic Multilingual Plane)
+fn parse_format4(table: &[u8], ranges: &mut Vec<(u32, u32)>) -> Option<()> {
+    let nb_segments = read_u16(table, 6)? as usize / 2;
+    let ends = 14;
+    let starts = ends + 2 * nb_segments + 2;
+    let deltas = starts + 2 * nb_segments;
+    let range_offsets = deltas + 2 * nb_segments;
+
+    for segment in 0..nb_segments {
+        let end = read_u16(table, ends + 2 * segment)? as u32;
+        let start = read_u16(table, starts + 2 * segment)? as u32;
+        let delta = read_u16(table, deltas + 2 * segment)? as u32;
+        let range_offset = read_u16(table, range_offsets + 2 * segment)? as usize;
+
+        for c in start..=end {
+            let glyph = if range_offset == 0 {
+                (c + delta) & 0xFFFF
+            } else {
+                // Offset from the range offset itself, into the glyph array
+                let address = range_offsets + 2 * segment + range_offset + 2 * (c - start) as usize;
+                match read_u16(table, address).unwrap_or(0) as u32 {
+                    0 => 0,
+                    glyph => (glyph + delta) & 0xFFFF,
+                }
+            };
+            // Glyph 0 is the "missing glyph" box
+            if glyph != 0 {
+                ranges.push((c, c));
+            }
+        }
+    }
+    return Some(());
+}
+
+/// Segmented coverage (all planes)
+fn parse_format12(table: &[u8], ranges: &mut Vec<(u32, u32)>) -> Option<()> {
+    for group in 0..read_u32(table, 12)? as usize {
+        let record = 16 + group * 12;
+        let start = read_u32(table, record)?;
+        let end = read_u32(table, record + 4)?.min(char::MAX as u32);
+        let start_glyph = read_u32(table, record + 8)?;
+        // Glyph 0 is .notdef: the range actually starts with the next character
+        let start = match start_glyph {
+            0 => match start.checked_add(1) {
+                Some(start) => start,
+                None => continue,
+            },
+            _ => start,
+        };
+        if start <= end {
+            ranges.push((start, end));
+        }
+    }
+    return Some(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chandler42_covers_french_names() {
+        let charmap = CharMap::parse(CHANDLER42).unwrap();
+        for c in ['a', 'Z', 'é', 'ç', '’'] {
+            assert!(charmap.contains(c), "{c} should be covered");
+        }
+    }
+
+    #[test]
+    fn chandler42_lacks_vietnamese_and_euro() {
+        let charmap = CharMap::parse(CHANDLER42).unwrap();
+        assert!(!charmap.contains('ứ'));
+        assert!(!charmap.contains('€'));
+    }
+
+    #[test]
+    fn invalid_fonts_are_rejected() {
+        assert!(CharMap::parse(&[]).is_none());
+        assert!(CharMap::parse(b"not a font at all, just some text").is_none());
+        for len in [4, 12, 64, 512] {
+            assert!(CharMap::parse(&CHANDLER42[..len]).is_none());
+        }
+
+        // A table directory pointing past the end of the data
+        let mut directory = b"OTTO\x00\x01\x00\x10\x00\x00\x00\x00cmap\x00\x00\x00\x00".to_vec();
+        directory.extend([0xFF, 0xFF, 0xFF, 0xF0, 0x00, 0x00, 0x01, 0x00]);
+        assert!(CharMap::parse(&directory).is_none());
+    }
+
+    #[test]
+    fn format12_groups_ending_the_code_space_are_skipped() {
+        let mut table = vec![0x00, 0x0C, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, end, start_glyph) in [(0xFFFFFFFF, 0xFFFFFFFF, 0), (0x41, 0x5A, 1)] {
+            for value in [start, end, start_glyph] {
+                table.extend(u32::to_be_bytes(value));
+            }
+        }
+        let mut ranges = Vec::new();
+        assert!(parse_format12(&table, &mut ranges).is_some());
+        assert_eq!(ranges, vec![(0x41, 0x5A)]);
+    }
+}
@@ -0,0 +1,567 @@
+use crate::cache::ImageCache;
+use crate::error::Result;
+use crate::person::{Focus, Person, FILL_FLAG, FIT_FLAG};
+use crate::poster::RenderConfig;
+use crate::smartcrop;
+use crate::tools;
+use crate::validation::{self, Issue, ValidationConfig};
+use image::imageops::FilterType;
+use image::DynamicImage;
+use pdfium_render::prelude::PdfPoints;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// A rectangle of source image pixels
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Rectangle {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Everything needed to turn a source picture into the image embedded in its cell
+pub struct ImageJob<'a> {
+    pub name: &'a str,
+    pub data: &'a [u8],
+    /// Upright source dimensions
+    pub src_size: (u32, u32),
+    pub crop: Rectangle,
+    /// Width of the placed image, in PDF points
+    pub image_width: f32,
+    /// Focal point the crop was centred on, if any
+    pub focus: Option<Focus>,
+    /// Cell ratio of a crop still to be found by analyzing the picture (see
+    /// [smartcrop::smart_crop]) once it is decoded; `crop` is replaced then
+    pub pending_crop: Option<f32>,
+}
+
+/// How a picture is made to match its cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitMode {
+    /// Crop the picture so that it fills the whole cell
+    Fill,
+    /// Show the whole picture, as large as the cell allows, the rest of the cell being padded
+    Fit,
+}
+
+impl FitMode {
+    /// Get the mode of a picture: the one of its flags, if any, otherwise the `default` one
+    pub fn for_person(person: &Person, default: FitMode) -> FitMode {
+        if person.has_flag(FIT_FLAG) {
+            return FitMode::Fit;
+        }
+        if person.has_flag(FILL_FLAG) {
+            return FitMode::Fill;
+        }
+        return default;
+    }
+}
+
+/// How the part of a picture shown in a cell is chosen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CropStrategy {
+    /// Keep the top of portraits (less chance to cut hair) and the centre of landscapes
+    Top,
+    /// Keep the most interesting part (faces, details), see [smartcrop::smart_crop]
+    Smart,
+}
+
+impl CropStrategy {
+    /// Names accepted by `from_name` (case insensitive)
+    pub const NAMES: &'static [&'static str] = &["top", "smart"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_ascii_lowercase().as_str() {
+            "top" => Some(CropStrategy::Top),
+            "smart" => Some(CropStrategy::Smart),
+            _ => None,
+        };
+    }
+}
+
+/// Compute the crop of each picture (of upright `sizes`) for cells of `cell_ratio`.
+/// Fitted pictures are not cropped at all; otherwise a picture focal point, when given,
+/// overrides the strategy.
+/// Crops which require analyzing pictures are taken from the `cache`; they are None when unknown,
+/// to be found when pictures are decoded (see [prepare_images]).
+pub fn compute_crops(
+    pictures: &[Person],
+    sizes: &[(u32, u32)],
+    cell_ratio: f32,
+    config: &RenderConfig,
+    cache: &ImageCache,
+) -> Vec<Option<Rectangle>> {
+    // Crops which do not depend on the picture content
+    let fixed: Vec<Option<Rectangle>> = pictures
+        .iter()
+        .zip(sizes)
+        .map(|(person, (width, height))| {
+            if FitMode::for_person(person, config.fit_mode) == FitMode::Fit {
+                return Some(Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: *width,
+                    height: *height,
+                });
+            }
+            return match (person.focus, config.crop_strategy) {
+                (Some(focus), _) => {
+                    Some(crop_to_fit_cell(*width, *height, cell_ratio, Some(focus)))
+                }
+                (None, CropStrategy::Top) => {
+                    Some(crop_to_fit_cell(*width, *height, cell_ratio, None))
+                }
+                (None, CropStrategy::Smart) => None,
+            };
+        })
+        .collect();
+
+    return pictures
+        .iter()
+        .zip(fixed)
+        .map(|(person, fixed)| {
+            fixed.or_else(|| cache.get_crop(&smart_crop_key(&person.data, cell_ratio)))
+        })
+        .collect();
+}
+
+/// Cache key of the smart crop of a picture for cells of `cell_ratio`
+fn smart_crop_key(data: &[u8], cell_ratio: f32) -> String {
+    return ImageCache::key(data, &format!("smartcrop-v1;ratio={cell_ratio:.4}"));
+}
+
+/// Compute how to crop image to make sure it will fill cell completely.
+/// With a `focus`, the window is zoomed and centred on the focal point (as far as image bounds allow).
+pub fn crop_to_fit_cell(
+    src_width: u32,
+    src_height: u32,
+    cell_ratio: f32,
+    focus: Option<Focus>,
+) -> Rectangle {
+    if let Some(focus) = focus {
+        let full = crop_to_fit_cell(src_width, src_height, cell_ratio, None);
+        let zoom = focus.zoom.max(1.);
+        let width = ((full.width as f32 / zoom).round() as u32).max(1);
+        let height = ((full.height as f32 / zoom).round() as u32).max(1);
+        let center_x = focus.x.clamp(0., 1.) * src_width as f32;
+        let center_y = focus.y.clamp(0., 1.) * src_height as f32;
+        return Rectangle {
+            x: (center_x - width as f32 / 2.).clamp(0., (src_width - width) as f32) as u32,
+            y: (center_y - height as f32 / 2.).clamp(0., (src_height - height) as f32) as u32,
+            width,
+            height,
+        };
+    }
+
+    let x: u32;
+    let y: u32;
+    let width: u32;
+    let height: u32;
+    let image_ratio = src_height as f32 / src_width as f32;
+    if cell_ratio > image_ratio {
+        // Cell is proportionally taller than image => need to crop image left and/or right
+        height = src_height;
+        width = (height as f32 / cell_ratio) as u32;
+        x = (src_width - width) / 2;
+        y = 0;
+    } else {
+        // Need to crop image top and/or bottom
+        // To respect faces, crop bottom (less chance to cut top of hair)
+        width = src_width;
+        height = (width as f32 * cell_ratio) as u32;
+        x = 0;
+        y = 0; // (src_height - height) / 2;
+    }
+    return Rectangle {
+        x,
+        y,
+        width,
+        height,
+    };
+}
+
+/// Sharpening applied after downsampling (see [DynamicImage::unsharpen])
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    pub sigma: f32,
+    pub threshold: i32,
+}
+
+/// How images are downsampled to reach the target resolution
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resampling {
+    pub filter: FilterType,
+    pub sharpen: Option<Sharpen>,
+}
+
+impl Default for Resampling {
+    fn default() -> Self {
+        return Resampling {
+            filter: FilterType::Lanczos3,
+            sharpen: None,
+        };
+    }
+}
+
+/// Get the resolution of the cropped part of a picture, once placed `image_width` points wide
+pub fn effective_dpi(crop: &Rectangle, image_width: f32) -> u32 {
+    return tools::compute_dpi(crop.width as usize, PdfPoints::new(image_width).to_cm());
+}
+
+/// Get the (width, height) a cropped picture must be resized to, to be placed `image_width` points
+/// wide at `max_dpi` (aspect ratio is kept); None when its resolution does not exceed `max_dpi`
+pub fn target_size(crop: &Rectangle, image_width: f32, max_dpi: u32) -> Option<(u32, u32)> {
+    let dst_width = (PdfPoints::new(image_width).to_inches() * max_dpi as f32).round() as u32;
+    if dst_width == 0 || dst_width >= crop.width {
+        return None;
+    }
+    let dst_height = (crop.height as f32 * dst_width as f32 / crop.width as f32).round() as u32;
+    return Some((dst_width, dst_height.max(1)));
+}
+
+/// Describe everything but the source bytes which affects a processed image, for its cache key.
+/// Bump the version whenever the processing itself changes.
+fn processing_params(job: &ImageJob, config: &RenderConfig) -> String {
+    let crop = &job.crop;
+    let resampling = &config.resampling;
+    return format!(
+        "v2;upright;crop={},{},{},{};focus={};width={:.3}pt;max_dpi={};filter={:?};sharpen={};color=rgb8;quality={}",
+        crop.x,
+        crop.y,
+        crop.width,
+        crop.height,
+        job.focus
+            .map(|f| format!("{},{},{}", f.x, f.y, f.zoom))
+            .unwrap_or(String::from("none")),
+        job.image_width,
+        config
+            .max_dpi
+            .map(|n| u32::to_string(&n))
+            .unwrap_or(String::from("native")),
+        resampling.filter,
+        resampling
+            .sharpen
+            .map(|s| format!("{},{}", s.sigma, s.threshold))
+            .unwrap_or(String::from("none")),
+        config.jpeg_quality
+    );
+}
+
+/// Decode and crop a picture, finding its crop first when it is pending. Return the cropped image
+/// and the crop.
+pub fn decode_and_crop(job: &ImageJob) -> Result<(DynamicImage, Rectangle)> {
+    // Actually decode JPEG data (pixels are turned upright, matching the crop computed above)
+    let src_image = tools::decode_image(job.data, job.name)?;
+
+    let crop = match job.pending_crop {
+        Some(cell_ratio) => smartcrop::smart_crop(&src_image, cell_ratio),
+        None => job.crop,
+    };
+
+    // Actually crop image data
+    let cropped = src_image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+    return Ok((cropped, crop));
+}
+
+/// Resize a cropped picture as configured and JPEG-encode it
+pub fn resize_and_encode(
+    cropped: &DynamicImage,
+    job: &ImageJob,
+    config: &RenderConfig,
+) -> Result<Vec<u8>> {
+    let name = job.name;
+    let resampling = &config.resampling;
+
+    // Resize the image if needed to target max DPI
+    let target = config
+        .max_dpi
+        .and_then(|max_dpi| target_size(&job.crop, job.image_width, max_dpi));
+    let resized = match target {
+        Some((dst_width, dst_height)) => {
+            // Target size already keeps the aspect ratio
+            let resized = cropped.resize_exact(dst_width, dst_height, resampling.filter);
+            match resampling.sharpen {
+                Some(sharpen) => resized.unsharpen(sharpen.sigma, sharpen.threshold),
+                None => resized,
+            }
+        }
+        None => cropped.clone(), // Nothing to do, image does not reach target DPI
+    };
+
+    // Get JPEG-encoded data
+    return tools::encode_to_jpeg(resized, config.jpeg_quality, name);
+}
+
+/// Get the crop of a job, its images for each of the `configs` (completing the `cached` ones), and
+/// the issues found checking the picture (with a `validation` config, the source is decoded even
+/// when all images are cached)
+fn complete_images(
+    job: &ImageJob,
+    configs: &[RenderConfig],
+    mut cached: Vec<Option<Vec<u8>>>,
+    validation: Option<&ValidationConfig>,
+) -> Result<(Rectangle, Vec<Vec<u8>>, Vec<Issue>)> {
+    let mut crop = job.crop;
+    let mut issues = Vec::new();
+    if cached.iter().any(|c| c.is_none()) || validation.is_some() {
+        let cropped;
+        (cropped, crop) = decode_and_crop(job)?;
+        let job = &ImageJob {
+            crop,
+            pending_crop: None,
+            ..*job
+        };
+        if let Some(validation) = validation {
+            issues = validation::validate(job, &cropped, validation);
+        }
+        for (config, image) in configs.iter().zip(cached.iter_mut()) {
+            if image.is_none() {
+                *image = Some(resize_and_encode(&cropped, job, config)?);
+            }
+        }
+    }
+    return Ok((
+        crop,
+        cached.into_iter().map(|c| c.unwrap()).collect(),
+        issues,
+    ));
+}
+
+/// Get the key under which the issues found checking a picture are cached: they depend on
+/// the source, on its crop and placement, and on the thresholds
+fn validation_key(job: &ImageJob, validation: &ValidationConfig) -> String {
+    let crop = &job.crop;
+    let params = format!(
+        "validation-v1;crop={},{},{},{};focus={};width={:.3}pt;min_dpi={};max_crop_loss={};min_brightness={};max_clipped={};min_sharpness={}",
+        crop.x,
+        crop.y,
+        crop.width,
+        crop.height,
+        job.focus
+            .map(|f| format!("{},{},{}", f.x, f.y, f.zoom))
+            .unwrap_or(String::from("none")),
+        job.image_width,
+        validation.min_dpi,
+        validation.max_crop_loss,
+        validation.min_brightness,
+        validation.max_clipped,
+        validation.min_sharpness,
+    );
+    return ImageCache::key(job.data, &params);
+}
+
+/// An image ready to be embedded
+pub struct PreparedImage {
+    /// JPEG-encoded data
+    pub bytes: Vec<u8>,
+    /// Whether it was found in the cache
+    pub cache_hit: bool,
+    /// Problems found checking the picture (the same for every config)
+    pub issues: Vec<Issue>,
+}
+
+/// Prepare the images of all `jobs` for each of the `configs` (which should only differ by the
+/// images encoding), reusing cached ones. Return them by config, then in jobs order.
+/// Each source missing some image is decoded and cropped once, then resized and encoded for every
+/// config. When the first config enables validation, pictures are checked too (the issues being
+/// cached like images, a source is decoded only when they are unknown). Pending crops are found
+/// at the same time (and kept in the cache), and set in `jobs`.
+/// Sources are processed in parallel (see [thread_count]); the result does not depend on the
+/// number of threads.
+pub fn prepare_images(
+    jobs: &mut [ImageJob],
+    configs: &[RenderConfig],
+    cache: &mut ImageCache,
+) -> Result<Vec<Vec<PreparedImage>>> {
+    // Cache keys, by job then config (unknown until the crop is)
+    let image_keys = |job: &ImageJob| -> Vec<String> {
+        return configs
+            .iter()
+            .map(|config| ImageCache::key(job.data, &processing_params(job, config)))
+            .collect();
+    };
+    let mut keys: Vec<Vec<String>> = jobs
+        .iter()
+        .map(|job| match job.pending_crop {
+            Some(_) => Vec::new(),
+            None => image_keys(job),
+        })
+        .collect();
+
+    // Try to load from cached bytes first
+    let cached: Vec<Vec<Option<Vec<u8>>>> = keys
+        .iter()
+        .map(|job_keys| {
+            if job_keys.is_empty() {
+                return vec![None; configs.len()];
+            }
+            return job_keys.iter().map(|key| cache.get(key)).collect();
+        })
+        .collect();
+    let missing: Vec<Vec<bool>> = cached
+        .iter()
+        .map(|images| images.iter().map(|i| i.is_none()).collect())
+        .collect();
+
+    // Issues already found for sources whose crop is known
+    let validation = configs
+        .first()
+        .and_then(|config| config.validation.as_ref());
+    let mut cached_issues: Vec<Option<Vec<Issue>>> = jobs
+        .iter()
+        .map(|job| match (validation, job.pending_crop) {
+            (Some(validation), None) => cache.get_issues(&validation_key(job, validation)),
+            _ => None,
+        })
+        .collect();
+
+    // Perform image transforms and re-encoding of the others (and checks of unknown issues)
+    let inputs: Vec<_> = jobs
+        .iter()
+        .zip(cached)
+        .zip(cached_issues.iter())
+        .map(|((job, cached), issues)| (job, cached, validation.filter(|_| issues.is_none())))
+        .collect();
+    let results = parallel_map(
+        inputs,
+        thread_count(configs.first()),
+        |(job, cached, validation)| complete_images(job, configs, cached, validation),
+    );
+
+    // Cache final image data on disk (in order, so that the first error is the one reported)
+    let mut images: Vec<Vec<PreparedImage>> = configs.iter().map(|_| Vec::new()).collect();
+    for (i, result) in results.into_iter().enumerate() {
+        let (crop, job_images, issues) = result?;
+        let job = &mut jobs[i];
+        if let Some(cell_ratio) = job.pending_crop.take() {
+            cache.put_crop(&smart_crop_key(job.data, cell_ratio), job.data, crop);
+            job.crop = crop;
+            keys[i] = image_keys(job);
+        }
+        let issues = match (cached_issues[i].take(), validation) {
+            (Some(cached), _) => cached,
+            (None, Some(validation)) => {
+                cache.put_issues(&validation_key(job, validation), job.data, issues.clone());
+                issues
+            }
+            (None, None) => issues,
+        };
+        for (c, bytes) in job_images.into_iter().enumerate() {
+            if missing[i][c] {
+                cache.put(&keys[i][c], jobs[i].name, jobs[i].data, &bytes)?;
+            }
+            images[c].push(PreparedImage {
+                bytes,
+                cache_hit: !missing[i][c],
+                issues: issues.clone(),
+            });
+        }
+    }
+
+    return Ok(images);
+}
+
+/// Get the number of threads to process images with: `jobs` of the config (all cores by default)
+pub fn thread_count(config: Option<&RenderConfig>) -> usize {
+    return config
+        .and_then(|config| config.jobs)
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+}
+
+/// Apply `f` to all `items` using up to `nb_threads` threads, keeping items order
+pub fn parallel_map<T: Send, R: Send>(
+    items: Vec<T>,
+    nb_threads: usize,
+    f: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let nb_threads = nb_threads.clamp(1, items.len().max(1));
+    let inputs: Vec<Mutex<Option<T>>> = items.into_iter().map(|i| Mutex::new(Some(i))).collect();
+    let outputs: Vec<Mutex<Option<R>>> = inputs.iter().map(|_| Mutex::new(None)).collect();
+
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..nb_threads {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= inputs.len() {
+                    break;
+                }
+                let item = inputs[i]
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .expect("Items are taken once");
+                *outputs[i].lock().unwrap() = Some(f(item));
+            });
+        }
+    });
+
+    return outputs
+        .into_iter()
+        .map(|o| o.into_inner().unwrap().expect("Every item was processed"))
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn focus(x: f32, y: f32, zoom: f32) -> Option<Focus> {
+        return Some(Focus { x, y, zoom });
+    }
+
+    fn rectangle(x: u32, y: u32, width: u32, height: u32) -> Rectangle {
+        return Rectangle {
+            x,
+            y,
+            width,
+            height,
+        };
+    }
+
+    #[test]
+    fn focus_in_corners_keeps_crop_within_image() {
+        // Portrait and landscape sources in square cells
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(0., 0., 1.)),
+            rectangle(0, 0, 400, 400)
+        );
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(1., 1., 1.)),
+            rectangle(0, 200, 400, 400)
+        );
+        assert_eq!(
+            crop_to_fit_cell(600, 400, 1., focus(0., 0., 1.)),
+            rectangle(0, 0, 400, 400)
+        );
+        assert_eq!(
+            crop_to_fit_cell(600, 400, 1., focus(1., 1., 1.)),
+            rectangle(200, 0, 400, 400)
+        );
+    }
+
+    #[test]
+    fn zoom_narrows_crop_around_focus() {
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(0.5, 0.5, 2.)),
+            rectangle(100, 200, 200, 200)
+        );
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(0., 0., 4.)),
+            rectangle(0, 0, 100, 100)
+        );
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(1., 1., 4.)),
+            rectangle(300, 500, 100, 100)
+        );
+        // Zooming out is not possible
+        assert_eq!(
+            crop_to_fit_cell(400, 600, 1., focus(0.5, 0.5, 0.5)),
+            crop_to_fit_cell(400, 600, 1., focus(0.5, 0.5, 1.))
+        );
+    }
+}
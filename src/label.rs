@@ -0,0 +1,239 @@
+use pdfium_render::prelude::PdfColor;
+
+/// Appended to labels too long to fit, even on two lines
+const ELLIPSIS: &str = "...";
+
+/// How labels are laid out below pictures
+#[derive(Clone, Debug)]
+pub struct LabelConfig {
+    /// Preferred font size
+    pub font_size: f32,
+    /// Font size labels may shrink to before being wrapped
+    pub min_font_size: f32,
+    /// Distance between baselines of wrapped lines, relative to the font size
+    pub line_spacing: f32,
+    pub subtitle: SubtitleConfig,
+}
+
+/// How the secondary caption (role, team...) is shown under names
+#[derive(Clone, Debug)]
+pub struct SubtitleConfig {
+    pub font_size: f32,
+    /// Font size subtitles may shrink to before being cut (they are never wrapped)
+    pub min_font_size: f32,
+    pub color: PdfColor,
+}
+
+impl Default for LabelConfig {
+    fn default() -> Self {
+        return LabelConfig {
+            font_size: 5.,
+            min_font_size: 3.5,
+            line_spacing: 1.15,
+            subtitle: SubtitleConfig {
+                font_size: 4.,
+                min_font_size: 3.,
+                color: PdfColor::GREY_40,
+            },
+        };
+    }
+}
+
+impl LabelConfig {
+    /// Height of the part of the label band holding names, large enough for two wrapped lines
+    pub fn name_height(&self) -> f32 {
+        return self
+            .font_size
+            .max(2. * self.min_font_size * self.line_spacing)
+            * 1.2;
+    }
+
+    /// Height of the band reserved below each picture. With `subtitles`, the band grows by a
+    /// line for them, for every cell (names stay aligned whether people have a subtitle or not).
+    pub fn band_height(&self, subtitles: bool) -> f32 {
+        if subtitles {
+            return self.name_height() + self.subtitle.font_size * 1.2;
+        }
+        return self.name_height();
+    }
+}
+
+/// A label laid out to fit a width
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelLayout {
+    pub font_size: f32,
+    /// One or two lines
+    pub lines: Vec<String>,
+}
+
+/// Fit `text` in `max_width`: shrink it down to the minimum font size, then wrap it on two lines
+/// at a space or a hyphen, and finally cut it with an ellipsis.
+/// `measure` gives the width of a text at a font size.
+pub fn layout_label<E>(
+    text: &str,
+    max_width: f32,
+    config: &LabelConfig,
+    measure: impl Fn(&str, f32) -> Result<f32, E>,
+) -> Result<LabelLayout, E> {
+    if let Some(layout) = shrink_to_fit(
+        text,
+        max_width,
+        config.font_size,
+        config.min_font_size,
+        &measure,
+    )? {
+        return Ok(layout);
+    }
+
+    let font_size = config.min_font_size;
+    let fits = |line: &str| -> Result<bool, E> { Ok(measure(line, font_size)? <= max_width) };
+
+    // Best split: the one with the narrowest widest line
+    let mut best: Option<(f32, String, String)> = None;
+    for (first, second) in break_candidates(text) {
+        let widest = measure(&first, font_size)?.max(measure(&second, font_size)?);
+        if best.as_ref().is_none_or(|(w, _, _)| widest < *w) {
+            best = Some((widest, first, second));
+        }
+    }
+
+    let lines = match best {
+        Some((widest, first, second)) if widest <= max_width => vec![first, second],
+        _ => {
+            // No balanced split fits: fill the first line as much as possible, cut the second one
+            let mut greedy = None;
+            for (first, second) in break_candidates(text) {
+                if fits(&first)? {
+                    greedy = Some((first, second));
+                }
+            }
+            match greedy {
+                Some((first, second)) => vec![first, ellipsize(&second, &fits)?],
+                None => vec![ellipsize(text, &fits)?],
+            }
+        }
+    };
+
+    return Ok(LabelLayout { font_size, lines });
+}
+
+/// Fit `text` in `max_width` on a single line: shrink it down to `min_font_size`, then cut it
+/// with an ellipsis
+pub fn layout_line<E>(
+    text: &str,
+    max_width: f32,
+    font_size: f32,
+    min_font_size: f32,
+    measure: impl Fn(&str, f32) -> Result<f32, E>,
+) -> Result<LabelLayout, E> {
+    if let Some(layout) = shrink_to_fit(text, max_width, font_size, min_font_size, &measure)? {
+        return Ok(layout);
+    }
+    let fits = |line: &str| -> Result<bool, E> { Ok(measure(line, min_font_size)? <= max_width) };
+    return Ok(single_line(&ellipsize(text, &fits)?, min_font_size));
+}
+
+/// Lay `text` out on a single line, if a size between `min_font_size` and `font_size` fits
+fn shrink_to_fit<E>(
+    text: &str,
+    max_width: f32,
+    font_size: f32,
+    min_font_size: f32,
+    measure: &impl Fn(&str, f32) -> Result<f32, E>,
+) -> Result<Option<LabelLayout>, E> {
+    // Text width is proportional to the font size
+    let width = measure(text, font_size)?;
+    if width <= max_width || text.is_empty() {
+        return Ok(Some(single_line(text, font_size)));
+    }
+    let fitting_size = font_size * max_width / width;
+    if fitting_size >= min_font_size {
+        return Ok(Some(single_line(text, fitting_size)));
+    }
+    return Ok(None);
+}
+
+fn single_line(text: &str, font_size: f32) -> LabelLayout {
+    return LabelLayout {
+        font_size,
+        lines: vec![text.to_string()],
+    };
+}
+
+/// All the ways to split a text on two lines: at spaces (dropped) or after hyphens (kept)
+fn break_candidates(text: &str) -> Vec<(String, String)> {
+    let mut candidates = Vec::new();
+    for (i, c) in text.char_indices() {
+        let (first, second) = match c {
+            ' ' => (&text[..i], &text[i + 1..]),
+            '-' => (&text[..i + 1], &text[i + 1..]),
+            _ => continue,
+        };
+        let (first, second) = (first.trim_end(), second.trim_start());
+        if !first.is_empty() && !second.is_empty() {
+            candidates.push((first.to_string(), second.to_string()));
+        }
+    }
+    return candidates;
+}
+
+/// Cut a line, adding an ellipsis, so that it fits
+fn ellipsize<E>(line: &str, fits: &impl Fn(&str) -> Result<bool, E>) -> Result<String, E> {
+    let chars: Vec<char> = line.chars().collect();
+    for len in (0..chars.len()).rev() {
+        let prefix: String = chars[..len].iter().collect();
+        let candidate = format!("{}{ELLIPSIS}", prefix.trim_end());
+        if fits(&candidate)? {
+            return Ok(candidate);
+        }
+    }
+    return Ok(ELLIPSIS.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    /// Every character is half as wide as the font size
+    fn measure(text: &str, font_size: f32) -> Result<f32, Infallible> {
+        return Ok(text.chars().count() as f32 * font_size / 2.);
+    }
+
+    fn label(text: &str, max_width: f32) -> LabelLayout {
+        return layout_label(text, max_width, &LabelConfig::default(), measure).unwrap();
+    }
+
+    #[test]
+    fn labels_shrink_before_wrapping() {
+        assert_eq!(label("Jean", 20.), single_line("Jean", 5.));
+        assert_eq!(label("Jean Dupont", 22.), single_line("Jean Dupont", 4.));
+    }
+
+    #[test]
+    fn labels_wrap_at_spaces_or_hyphens() {
+        let layout = label("Jean-Pierre Dupont", 20.);
+        assert_eq!(layout.font_size, 3.5);
+        assert_eq!(layout.lines, vec!["Jean-Pierre", "Dupont"]);
+
+        let layout = label("Marie-Christine", 16.);
+        assert_eq!(layout.lines, vec!["Marie-", "Christine"]);
+    }
+
+    #[test]
+    fn labels_are_cut_with_an_ellipsis() {
+        assert_eq!(label("Maximilienne", 10.).lines, vec!["Ma..."]);
+        assert_eq!(
+            label("Ana Maximiliendupontel", 14.).lines,
+            vec!["Ana", "Maxim..."]
+        );
+    }
+
+    #[test]
+    fn lines_shrink_then_are_cut() {
+        let line = |max_width| layout_line("Captain", max_width, 4., 3., measure).unwrap();
+        assert_eq!(line(20.), single_line("Captain", 4.));
+        assert_eq!(line(12.), single_line("Captain", 4. * 12. / 14.));
+        assert_eq!(line(8.), single_line("Ca...", 3.));
+    }
+}
@@ -0,0 +1,513 @@
+use crate::error::{Error, Result};
+use crate::poster::RenderConfig;
+
+/// Number of rows and columns of cells on a page
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Grid {
+    pub rows: i32,
+    pub columns: i32,
+}
+
+impl Grid {
+    pub fn cells(&self) -> i32 {
+        return self.rows * self.columns;
+    }
+
+    /// Number of rows needed to layout groups, each one starting on a fresh row
+    pub fn rows_for(&self, group_sizes: &[usize]) -> i32 {
+        return group_sizes
+            .iter()
+            .map(|n| (*n as i32 + self.columns - 1) / self.columns)
+            .sum();
+    }
+}
+
+/// An area of a page, in PDF points (origin is at bottom left)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Area {
+    pub left: f32,
+    pub bottom: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Area {
+    pub fn top(&self) -> f32 {
+        return self.bottom + self.height;
+    }
+
+    pub fn right(&self) -> f32 {
+        return self.left + self.width;
+    }
+}
+
+/// Where something (a picture, a section header) lands in the document
+#[derive(Clone, Copy, Debug)]
+pub struct Placement {
+    /// Index of the placed item (picture or group)
+    pub index: usize,
+    pub page: usize,
+    pub area: Area,
+}
+
+/// Result of laying out pictures on pages
+pub struct PageLayout {
+    pub nb_pages: usize,
+    pub cell_width: f32,
+    pub cell_height: f32,
+    /// One placement per picture, in pictures order
+    pub cells: Vec<Placement>,
+    /// One placement per section header (empty when there are no sections)
+    pub headers: Vec<Placement>,
+}
+
+/// How content areas ("frames") are arranged on pages
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LayoutMode {
+    /// A single frame per page
+    Grid,
+    /// Two facing frames separated by a central gutter (`center_margin` on each side of the fold).
+    /// The paper size is the one of the whole spread: it is printed either as a single sheet
+    /// with a central fold, or as two separate facing pages of half its width.
+    Spread {
+        center_margin: f32,
+        facing_pages: bool,
+    },
+}
+
+impl LayoutMode {
+    pub fn frames_per_spread(&self) -> usize {
+        return match self {
+            LayoutMode::Grid => 1,
+            LayoutMode::Spread { .. } => 2,
+        };
+    }
+
+    /// Get the (width, height) of the PDF pages for a given paper size
+    pub fn page_size(&self, paper_width: f32, paper_height: f32) -> (f32, f32) {
+        return match self {
+            LayoutMode::Spread {
+                facing_pages: true, ..
+            } => (paper_width / 2., paper_height),
+            _ => (paper_width, paper_height),
+        };
+    }
+
+    /// Check that frames are not empty once margins, header and footer are taken out
+    pub fn check_frames(
+        &self,
+        paper_width: f32,
+        paper_height: f32,
+        config: &RenderConfig,
+    ) -> Result<()> {
+        let (_, frame) = self.frame(0, paper_width, paper_height, config);
+        if frame.width <= 0. || frame.height <= 0. {
+            return Err(Error::NoRoomForGrid {
+                width: frame.width,
+                height: frame.height,
+            });
+        }
+        return Ok(());
+    }
+
+    /// Get the page index and content area of the `index`-th frame
+    pub fn frame(
+        &self,
+        index: usize,
+        paper_width: f32,
+        paper_height: f32,
+        config: &RenderConfig,
+    ) -> (usize, Area) {
+        let (page, left, width) = match *self {
+            LayoutMode::Grid => (
+                index,
+                config.page_hmargin,
+                paper_width - config.page_hmargin * 2.,
+            ),
+            LayoutMode::Spread {
+                center_margin,
+                facing_pages,
+            } => {
+                let width = paper_width / 2. - config.page_hmargin - center_margin;
+                let on_left_page = index.is_multiple_of(2);
+                match (facing_pages, on_left_page) {
+                    (false, true) => (index / 2, config.page_hmargin, width),
+                    (false, false) => (index / 2, paper_width / 2. + center_margin, width),
+                    (true, true) => (index, config.page_hmargin, width),
+                    (true, false) => (index, center_margin, width),
+                }
+            }
+        };
+
+        // Header and footer bands take room from the grid
+        let header = config.header.as_ref().map_or(0., |h| h.height);
+        let footer = config.footer.as_ref().map_or(0., |f| f.height);
+        return (
+            page,
+            Area {
+                left,
+                bottom: config.page_vmargin + footer,
+                width,
+                height: paper_height - config.page_vmargin * 2. - header - footer,
+            },
+        );
+    }
+}
+
+/// Photo areas within this ratio of the best one are considered as good;
+/// among those, the grid with the fewest empty slots wins
+const AREA_TOLERANCE: f32 = 0.02;
+
+/// Tolerance used when checking if a row still fits in a frame
+const EPSILON: f32 = 0.01;
+
+/// Pick the grid which gives the largest photos when pictures are spread over content areas of
+/// the `frame` size, `frames_per_spread` at a time.
+/// `group_sizes` gives the number of pictures of each section (each one starting on a fresh row,
+/// below a band of its `header_heights`, 0 for none); use a single group without a header for a
+/// plain grid.
+/// Cells are considered usable only up to the `photo_ratio` (height / width) of a typical photo,
+/// any extra space being lost to cropping; `label_height` is reserved below each photo.
+/// Photos are kept at least `config.min_photo_width` wide by using more spreads; when even one
+/// picture per frame cannot reach it, the grid giving the largest photos is returned.
+/// All dimensions are in PDF points. Returns None when the frame cannot hold a single cell.
+pub fn solve_grid(
+    group_sizes: &[usize],
+    frame: &Area,
+    frames_per_spread: usize,
+    config: &RenderConfig,
+    label_height: f32,
+    header_heights: &[f32],
+    photo_ratio: f32,
+) -> Option<Grid> {
+    let frames_per_spread = frames_per_spread.max(1) as i32;
+    let largest_group = group_sizes.iter().copied().max().unwrap_or(0).max(1) as i32;
+    let nb_pictures = group_sizes.iter().sum::<usize>() as i32;
+
+    // (grid, photo width) giving the largest photos so far, whatever their width
+    let mut largest: Option<(Grid, f32)> = None;
+    let mut nb_frames = frames_per_spread;
+    loop {
+        // (grid, photo width, photo area, empty slots)
+        let mut candidates: Vec<(Grid, f32, f32, i32)> = Vec::new();
+        for columns in 1..=largest_group {
+            // Rows are shared between frames
+            let mut grid = Grid { rows: 1, columns };
+            grid.rows = ((grid.rows_for(group_sizes) + nb_frames - 1) / nb_frames).max(1);
+
+            let (cell_width, cell_height) = cell_size(
+                group_sizes,
+                grid,
+                frame,
+                config,
+                label_height,
+                header_heights,
+            );
+            if cell_width <= 0. || cell_height <= 0. {
+                continue;
+            }
+
+            // Largest photo of the typical ratio which fits the cell
+            let photo_width = cell_width.min(cell_height / photo_ratio);
+            let area = photo_width * photo_width * photo_ratio;
+            candidates.push((
+                grid,
+                photo_width,
+                area,
+                grid.cells() * nb_frames - nb_pictures,
+            ));
+        }
+
+        if let Some(c) = candidates.iter().max_by(|a, b| a.1.total_cmp(&b.1)) {
+            if largest.is_none_or(|(_, width)| c.1 > width) {
+                largest = Some((c.0, c.1));
+            }
+        }
+
+        let best_area = candidates
+            .iter()
+            .filter(|c| c.1 >= config.min_photo_width)
+            .map(|c| c.2)
+            .fold(0., f32::max);
+        let best = candidates
+            .iter()
+            .filter(|c| c.1 >= config.min_photo_width)
+            .filter(|c| c.2 >= best_area * (1. - AREA_TOLERANCE))
+            .min_by_key(|c| c.3)
+            .map(|c| c.0);
+        if best.is_some() {
+            return best;
+        }
+
+        // Beyond a row per frame, more frames do not make larger photos
+        if nb_frames >= nb_pictures {
+            return largest.map(|(grid, _)| grid);
+        }
+        nb_frames += frames_per_spread;
+    }
+}
+
+/// Flow groups of pictures in `grid` cells, top to bottom, then over as many frames
+/// (content areas) as needed. `frames` gives the page and area of each successive frame,
+/// all of the same size.
+/// Each group starts on a fresh row, below a header band when its `header_heights` is not zero
+/// (repeated when a group continues in the next frame).
+/// Cells shrink to leave room for the header bands, and for a `label_height` band below each
+/// cell (cells only hold the photos).
+pub fn flow_grid(
+    group_sizes: &[usize],
+    grid: Grid,
+    frames: impl Fn(usize) -> (usize, Area),
+    config: &RenderConfig,
+    label_height: f32,
+    header_heights: &[f32],
+) -> PageLayout {
+    let (mut page, mut frame) = frames(0);
+
+    let (cell_width, cell_height) = cell_size(
+        group_sizes,
+        grid,
+        &frame,
+        config,
+        label_height,
+        header_heights,
+    );
+
+    let mut layout = PageLayout {
+        nb_pages: 1,
+        cell_width,
+        cell_height,
+        cells: Vec::new(),
+        headers: Vec::new(),
+    };
+
+    // Top of the next thing to place in the current frame
+    let mut frame_index = 0;
+    let mut cursor = frame.top();
+    let mut frame_empty = true;
+    let mut picture = 0;
+
+    for (group, group_size) in group_sizes.iter().enumerate() {
+        let nb_rows = (*group_size as i32 + grid.columns - 1) / grid.columns;
+        let header_height = header_heights[group];
+        for row in 0..nb_rows {
+            // A group header always stays with the group first row
+            let header = if row == 0 { header_height } else { 0. };
+            if !frame_empty
+                && cursor - config.inner_vmargin - header - cell_height - label_height
+                    < frame.bottom - EPSILON
+            {
+                frame_index += 1;
+                (page, frame) = frames(frame_index);
+                cursor = frame.top();
+                frame_empty = true;
+            }
+
+            if !frame_empty {
+                cursor -= config.inner_vmargin;
+            }
+
+            // Repeat the header at the top of a frame when a group continues there
+            if header_height > 0. && (row == 0 || frame_empty) {
+                layout.headers.push(Placement {
+                    index: group,
+                    page,
+                    area: Area {
+                        left: frame.left,
+                        bottom: cursor - header_height,
+                        width: frame.width,
+                        height: header_height,
+                    },
+                });
+                cursor -= header_height;
+            }
+
+            let in_row = (*group_size as i32 - row * grid.columns).min(grid.columns);
+            for column in 0..in_row {
+                layout.cells.push(Placement {
+                    index: picture,
+                    page,
+                    area: Area {
+                        left: frame.left + column as f32 * (cell_width + config.inner_hmargin),
+                        bottom: cursor - cell_height,
+                        width: cell_width,
+                        height: cell_height,
+                    },
+                });
+                picture += 1;
+            }
+
+            cursor -= cell_height + label_height;
+            frame_empty = false;
+        }
+    }
+
+    layout.nb_pages = page + 1;
+    return layout;
+}
+
+/// Get the (width, height) of the cells of `grid` in a frame, once header and label bands are
+/// taken out (see [flow_grid])
+fn cell_size(
+    group_sizes: &[usize],
+    grid: Grid,
+    frame: &Area,
+    config: &RenderConfig,
+    label_height: f32,
+    header_heights: &[f32],
+) -> (f32, f32) {
+    // Leave room for all header bands when everything fits in one frame, otherwise for the band
+    // topping each frame (additional groups starting mid-frame push rows to the next frame)
+    let reserved = if grid.rows_for(group_sizes) <= grid.rows {
+        header_heights.iter().sum()
+    } else {
+        max_height(header_heights)
+    };
+    let cell_width =
+        (frame.width - config.inner_hmargin * (grid.columns - 1) as f32) / grid.columns as f32;
+    let cell_height = (frame.height - reserved - config.inner_vmargin * (grid.rows - 1) as f32)
+        / grid.rows as f32
+        - label_height;
+    return (cell_width, cell_height);
+}
+
+/// Height of the tallest header band (0 when there are none)
+fn max_height(header_heights: &[f32]) -> f32 {
+    return header_heights.iter().copied().fold(0., f32::max);
+}
+
+/// Get the median ratio (height / width) of a set of picture dimensions, or 1 if there are none
+pub fn typical_ratio(sizes: &[(u32, u32)]) -> f32 {
+    let mut ratios: Vec<f32> = sizes
+        .iter()
+        .filter(|(w, _)| *w > 0)
+        .map(|(w, h)| *h as f32 / *w as f32)
+        .collect();
+    if ratios.is_empty() {
+        return 1.;
+    }
+
+    ratios.sort_by(|a, b| a.total_cmp(b));
+    return ratios[ratios.len() / 2];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontConfig;
+    use crate::imaging::{CropStrategy, FitMode, Resampling};
+    use crate::label::LabelConfig;
+    use crate::poster::{PageOrientation, PaperSize};
+    use pdfium_render::prelude::PdfColor;
+
+    /// A3 landscape, in points
+    const PAPER: (f32, f32) = (1190.55, 841.89);
+    const LABEL_HEIGHT: f32 = 12.;
+
+    fn config(min_photo_width: f32) -> RenderConfig {
+        return RenderConfig {
+            paper_size: PaperSize::A3,
+            orientation: PageOrientation::Landscape,
+            page_hmargin: 28.,
+            page_vmargin: 28.,
+            inner_hmargin: 3.,
+            inner_vmargin: 4.,
+            max_dpi: None,
+            fit_mode: FitMode::Fill,
+            fit_background: PdfColor::WHITE,
+            crop_strategy: CropStrategy::Top,
+            resampling: Resampling::default(),
+            jpeg_quality: 75,
+            jobs: None,
+            min_photo_width,
+            labels: LabelConfig::default(),
+            fonts: FontConfig::default(),
+            header: None,
+            footer: None,
+            sections: None,
+            layout_mode: LayoutMode::Grid,
+            validation: None,
+            debug: false,
+        };
+    }
+
+    /// Solve then flow groups on A3 pages, with photos of a 4:3 portrait ratio
+    fn layout(config: &RenderConfig, group_sizes: &[usize], header_heights: &[f32]) -> PageLayout {
+        let frames = |index| config.layout_mode.frame(index, PAPER.0, PAPER.1, config);
+        let grid = solve_grid(
+            group_sizes,
+            &frames(0).1,
+            config.layout_mode.frames_per_spread(),
+            config,
+            LABEL_HEIGHT,
+            header_heights,
+            4. / 3.,
+        )
+        .unwrap();
+        return flow_grid(
+            group_sizes,
+            grid,
+            frames,
+            config,
+            LABEL_HEIGHT,
+            header_heights,
+        );
+    }
+
+    #[test]
+    fn plain_grid_fits_a_single_page() {
+        let layout = layout(&config(0.), &[12], &[0.]);
+        assert_eq!(layout.nb_pages, 1);
+        assert_eq!(layout.cells.len(), 12);
+        assert!(layout.cells.iter().all(|c| c.page == 0));
+    }
+
+    #[test]
+    fn min_photo_width_adds_pages() {
+        let min_photo_width = 57.;
+        assert_eq!(layout(&config(0.), &[500], &[0.]).nb_pages, 1);
+
+        let layout = layout(&config(min_photo_width), &[500], &[0.]);
+        assert!(layout.nb_pages > 1);
+        assert_eq!(layout.cells.len(), 500);
+        assert!(layout.cell_width >= min_photo_width);
+        assert!(layout.cell_height * 3. / 4. >= min_photo_width);
+    }
+
+    #[test]
+    fn headers_are_not_all_reserved_in_one_frame() {
+        // Headers alone are taller than a frame
+        let group_sizes = vec![1; 60];
+        let header_heights = vec![14.; 60];
+        let layout = layout(&config(20.), &group_sizes, &header_heights);
+
+        assert!(layout.cell_width > 0. && layout.cell_height > 0.);
+        assert!(layout.nb_pages < 60);
+        assert_eq!(layout.headers.len(), 60);
+        for (header, cell) in layout.headers.iter().zip(layout.cells.iter()) {
+            assert_eq!(header.page, cell.page);
+            assert!((header.area.bottom - cell.area.top()).abs() < EPSILON);
+            assert!(cell.area.bottom - LABEL_HEIGHT >= 28. - EPSILON);
+        }
+    }
+
+    #[test]
+    fn frames_without_room_for_a_cell_have_no_grid() {
+        let config = config(0.);
+        let (_, frame) = config.layout_mode.frame(0, PAPER.0, 60., &config);
+        let grid = solve_grid(&[4], &frame, 1, &config, LABEL_HEIGHT, &[0.], 1.);
+        assert_eq!(grid, None);
+    }
+
+    #[test]
+    fn too_many_rows_leave_empty_cells() {
+        let config = config(0.);
+        let frames = |index| config.layout_mode.frame(index, PAPER.0, PAPER.1, &config);
+        let grid = Grid {
+            rows: 200,
+            columns: 5,
+        };
+        let layout = flow_grid(&[1000], grid, frames, &config, LABEL_HEIGHT, &[0.]);
+        assert!(layout.cell_height <= 0.);
+    }
+}
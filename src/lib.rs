@@ -1,2 +1,19 @@
+pub mod cache;
+pub mod codepage;
+pub mod decoration;
+pub mod error;
+pub mod font;
+pub mod imaging;
+pub mod label;
+pub mod layout;
+pub mod manifest;
+pub mod overlay;
+pub mod person;
 pub mod poster;
-pub mod tools;
\ No newline at end of file
+pub mod report;
+pub mod smartcrop;
+pub mod source;
+pub mod tools;
+pub mod validation;
+
+pub use error::{Error, Result};
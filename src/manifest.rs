@@ -0,0 +1,164 @@
+use crate::error::{Error, Result};
+use crate::person::{Focus, Person};
+use crate::tools;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Names under which a manifest is looked up among the pictures
+const MANIFEST_FILE_NAMES: [&str; 2] = ["manifest.csv", "manifest.json"];
+
+/// Describe how to present the person pictured in `file`.
+/// All fields but `file` are optional; missing ones keep the values derived from the file name.
+/// In a sidecar (a JSON file named after the picture, e.g. "X.jpg.json"), `file` is implied.
+#[derive(Deserialize)]
+pub struct ManifestEntry {
+    #[serde(default)]
+    pub file: String,
+    pub name: Option<String>,
+    pub subtitle: Option<String>,
+    pub group: Option<String>,
+    pub sort_key: Option<String>,
+    #[serde(default)]
+    pub flags: Option<Flags>,
+    /// Focal point (0 to 1, from the top left corner) and zoom, see [Focus]
+    pub focus_x: Option<f32>,
+    pub focus_y: Option<f32>,
+    pub zoom: Option<f32>,
+}
+
+impl ManifestEntry {
+    /// Override what was derived from the file name
+    pub fn apply(&self, person: &mut Person) {
+        if let Some(name) = &self.name {
+            person.name = name.clone();
+        }
+        if self.subtitle.is_some() {
+            person.subtitle = self.subtitle.clone();
+        }
+        if self.group.is_some() {
+            person.group = self.group.clone();
+        }
+        if let Some(sort_key) = &self.sort_key {
+            person.sort_key = sort_key.clone();
+        }
+        if let Some(flags) = &self.flags {
+            person.flags = flags.to_vec();
+        }
+        if self.focus_x.is_some() || self.focus_y.is_some() || self.zoom.is_some() {
+            person.focus = Some(Focus {
+                x: self.focus_x.unwrap_or(0.5).clamp(0., 1.),
+                y: self.focus_y.unwrap_or(0.5).clamp(0., 1.),
+                zoom: self.zoom.unwrap_or(1.).max(1.),
+            });
+        }
+    }
+}
+
+/// Flags are either a list (JSON) or a single string with separated values (CSV)
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Flags {
+    List(Vec<String>),
+    Joined(String),
+}
+
+impl Flags {
+    fn to_vec(&self) -> Vec<String> {
+        return match self {
+            Flags::List(flags) => flags.clone(),
+            Flags::Joined(flags) => flags
+                .split([';', ',', ' '])
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect(),
+        };
+    }
+}
+
+/// A set of entries mapping picture files to the people they show (CSV with headers, or JSON array)
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Tell if a picture source entry is actually a manifest
+    pub fn is_manifest_name(file_name: &str) -> bool {
+        return MANIFEST_FILE_NAMES
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(file_name));
+    }
+
+    /// Look for a manifest named after a picture source, next to it ("X.zip" => "X.csv" or "X.json")
+    pub fn find_next_to(source_path: &Path) -> Option<PathBuf> {
+        return ["csv", "json"]
+            .iter()
+            .map(|ext| source_path.with_extension(ext))
+            .find(|p| p.is_file());
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(|e| Error::io(path, e))?;
+        return Manifest::parse(&path.to_string_lossy(), &bytes);
+    }
+
+    /// Parse manifest data, the format being guessed from the file name extension
+    pub fn parse(name: &str, bytes: &[u8]) -> Result<Self> {
+        let manifest_error = |source: Box<dyn std::error::Error + Send + Sync>| Error::Manifest {
+            name: name.to_string(),
+            source,
+        };
+
+        let entries: Vec<ManifestEntry> = if name.to_ascii_lowercase().ends_with(".json") {
+            serde_json::from_slice(bytes).map_err(|e| manifest_error(e.into()))?
+        } else {
+            csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(bytes)
+                .deserialize()
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|e| manifest_error(e.into()))?
+        };
+
+        return Ok(Manifest { entries });
+    }
+
+    /// Find the entry describing a picture file (names are compared once composed,
+    /// since macOS archives store them decomposed)
+    pub fn entry_for(&self, file_name: &str) -> Option<&ManifestEntry> {
+        let file_name = tools::normalize_unicode(file_name);
+        return self
+            .entries
+            .iter()
+            .find(|e| tools::normalize_unicode(&e.file) == file_name);
+    }
+
+    /// Override what was derived from the file name with the manifest entry, if any
+    pub fn apply(&self, person: &mut Person) {
+        if let Some(entry) = self.entry_for(&person.file_name) {
+            entry.apply(person);
+        }
+    }
+
+    /// Get the name of the picture described by a sidecar file ("X.jpg.json" => "X.jpg")
+    pub fn sidecar_target(file_name: &str) -> Option<&str> {
+        if Manifest::is_manifest_name(file_name) {
+            return None;
+        }
+        let len = file_name.len();
+        if len > 5 && file_name.is_char_boundary(len - 5) {
+            let (target, extension) = file_name.split_at(len - 5);
+            if extension.eq_ignore_ascii_case(".json") && target.contains('.') {
+                return Some(target);
+            }
+        }
+        return None;
+    }
+
+    /// Parse a sidecar file, describing a single picture
+    pub fn parse_sidecar(name: &str, bytes: &[u8]) -> Result<ManifestEntry> {
+        return serde_json::from_slice(bytes).map_err(|e| Error::Manifest {
+            name: name.to_string(),
+            source: e.into(),
+        });
+    }
+}
@@ -0,0 +1,148 @@
+use crate::layout::Area;
+use pdfium_render::prelude::*;
+
+pub const MARGIN_COLOR: PdfColor = PdfColor::GREY_40;
+pub const CELL_COLOR: PdfColor = PdfColor::new(0, 90, 255, 255);
+pub const LABEL_BAND_COLOR: PdfColor = PdfColor::new(0, 200, 80, 60);
+pub const CROP_COLOR: PdfColor = PdfColor::new(255, 140, 0, 255);
+pub const TEXT_COLOR: PdfColor = PdfColor::new(220, 0, 0, 255);
+pub const ISSUE_COLOR: PdfColor = PdfColor::new(255, 0, 0, 255);
+
+pub fn draw_debug_line(
+    page: &mut PdfPage,
+    debug_color: PdfColor,
+    x1: f32,
+    y1: f32,
+    x2: f32,
+    y2: f32,
+) -> Result<(), PdfiumError> {
+    page.objects_mut()
+        .create_path_object_line(
+            PdfPoints::new(x1),
+            PdfPoints::new(y1),
+            PdfPoints::new(x2),
+            PdfPoints::new(y2),
+            debug_color,
+            PdfPoints::new(1.0),
+        )?
+        .set_dash_array(
+            &[PdfPoints::new(2.0), PdfPoints::new(1.0)],
+            PdfPoints::zero(),
+        )?;
+
+    return Ok(());
+}
+
+/// Work around the fact that for some reason, the last drawn line is not dashed :)
+pub fn flush_debug_lines(page: &mut PdfPage) -> Result<(), PdfiumError> {
+    return draw_debug_line(page, PdfColor::WHITE.with_alpha(0), 0., 0., 0., 0.);
+}
+
+/// Outline an area; with a `dashed` outline when it does not delimit something actually drawn
+pub fn draw_debug_rect(
+    page: &mut PdfPage,
+    debug_color: PdfColor,
+    area: &Area,
+    dashed: bool,
+) -> Result<(), PdfiumError> {
+    if dashed {
+        draw_debug_line(
+            page,
+            debug_color,
+            area.left,
+            area.bottom,
+            area.right(),
+            area.bottom,
+        )?;
+        draw_debug_line(
+            page,
+            debug_color,
+            area.right(),
+            area.bottom,
+            area.right(),
+            area.top(),
+        )?;
+        draw_debug_line(
+            page,
+            debug_color,
+            area.right(),
+            area.top(),
+            area.left,
+            area.top(),
+        )?;
+        draw_debug_line(
+            page,
+            debug_color,
+            area.left,
+            area.top(),
+            area.left,
+            area.bottom,
+        )?;
+        return Ok(());
+    }
+
+    page.objects_mut().create_path_object_rect(
+        to_pdf_rect(area),
+        Some(debug_color),
+        Some(PdfPoints::new(0.5)),
+        None,
+    )?;
+    return Ok(());
+}
+
+/// Fill an area with a (preferably translucent) color
+pub fn fill_debug_rect(
+    page: &mut PdfPage,
+    debug_color: PdfColor,
+    area: &Area,
+) -> Result<(), PdfiumError> {
+    page.objects_mut()
+        .create_path_object_rect(to_pdf_rect(area), None, None, Some(debug_color))?;
+    return Ok(());
+}
+
+/// Flag an area: outline it, with a square in its top right corner
+pub fn draw_issue_marker(page: &mut PdfPage, area: &Area) -> Result<(), PdfiumError> {
+    let size = 6.;
+    let corner = Area {
+        left: area.right() - size,
+        bottom: area.top() - size,
+        width: size,
+        height: size,
+    };
+    page.objects_mut().create_path_object_rect(
+        to_pdf_rect(area),
+        Some(ISSUE_COLOR),
+        Some(PdfPoints::new(2.0)),
+        None,
+    )?;
+    fill_debug_rect(page, ISSUE_COLOR, &corner)?;
+    return Ok(());
+}
+
+/// Write some debug text, its top left corner being at (`left`, `top`)
+pub fn draw_debug_text<'a>(
+    document: &PdfDocument<'a>,
+    page: &mut PdfPage<'a>,
+    font: PdfFontToken,
+    text: String,
+    left: f32,
+    top: f32,
+) -> Result<(), PdfiumError> {
+    let font_size = 4.0;
+    let mut text_object = PdfPageTextObject::new(document, text, font, PdfPoints::new(font_size))?;
+    text_object.set_fill_color(TEXT_COLOR)?;
+    text_object.translate(PdfPoints::new(left), PdfPoints::new(top - font_size))?;
+    page.objects_mut().add_text_object(text_object)?;
+    return Ok(());
+}
+
+fn to_pdf_rect(area: &Area) -> PdfRect {
+    // Bottom left is (0,0)!
+    return PdfRect::new(
+        PdfPoints::new(area.bottom),
+        PdfPoints::new(area.left),
+        PdfPoints::new(area.top()),
+        PdfPoints::new(area.right()),
+    );
+}
@@ -0,0 +1,75 @@
+use regex::Regex;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// A person to show on the trombinoscope, with its picture
+pub struct Person {
+    /// Name of the picture file this person was loaded from
+    pub file_name: String,
+    /// Name displayed in the label
+    pub name: String,
+    /// Optional secondary caption
+    pub subtitle: Option<String>,
+    /// Group (faction, team...) this person belongs to
+    pub group: Option<String>,
+    /// Key used to order people
+    pub sort_key: String,
+    /// Free-form flags (see [Person::has_flag])
+    pub flags: Vec<String>,
+    /// Where to centre the crop, overriding the crop strategy
+    pub focus: Option<Focus>,
+    /// Raw picture data
+    pub data: Vec<u8>,
+}
+
+/// A manually chosen focal point, in coordinates normalized to the upright picture size
+/// ((0, 0) is top left), and how much to zoom around it (1 means "as large as the cell allows")
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Focus {
+    pub x: f32,
+    pub y: f32,
+    pub zoom: f32,
+}
+
+/// Flag marking people who must not appear on the poster
+pub const HIDDEN_FLAG: &str = "hidden";
+
+/// Flags overriding how a picture is made to match its cell (see `imaging::FitMode`)
+pub const FIT_FLAG: &str = "fit";
+pub const FILL_FLAG: &str = "fill";
+
+impl Person {
+    /// Build a person from a picture file name, following the "<group>_<name>.<ext>" convention.
+    /// Names without a group prefix are used as is (minus their extension).
+    pub fn from_file(file_name: String, data: Vec<u8>) -> Self {
+        static PREFIXED_NAME: OnceLock<Regex> = OnceLock::new();
+        let re = PREFIXED_NAME.get_or_init(|| Regex::new(r"^([^_]+)_([^.]+)\..+$").unwrap());
+
+        let (group, name) = match re.captures(&file_name) {
+            Some(caps) => (Some(caps[1].to_string()), caps[2].to_string()),
+            None => (None, file_stem(&file_name)),
+        };
+
+        return Person {
+            sort_key: file_name.clone(),
+            file_name,
+            name,
+            subtitle: None,
+            group,
+            flags: Vec::new(),
+            focus: None,
+            data,
+        };
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        return self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag));
+    }
+}
+
+fn file_stem(file_name: &str) -> String {
+    return Path::new(file_name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or(file_name.to_string());
+}
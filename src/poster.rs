@@ -1,234 +1,859 @@
+use crate::cache::ImageCache;
+use crate::decoration::{self, Band, BandConfig, BandValues};
+use crate::error::{Error, Result};
+use crate::font::{FontConfig, FontRole, Fonts, LoadedFonts, MissingGlyphs, TextStyle};
+use crate::imaging::{self, CropStrategy, FitMode, ImageJob, Resampling};
+use crate::label::{self, LabelConfig};
+use crate::layout::{self, Area, Grid, LayoutMode};
+use crate::overlay;
+use crate::person::Person;
+use crate::report::{DocumentReport, ImageReport};
 use crate::tools;
-use image::imageops::FilterType;
-use image::{self, DynamicImage};
-use imagesize;
+use crate::validation::ValidationConfig;
 use pdfium_render::prelude::*;
+use std::collections::HashMap;
 use std::io::Cursor;
 
+/// Standard paper sizes, or custom dimensions (in PDF points) given in portrait orientation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaperSize {
+    A0,
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    Letter,
+    Tabloid,
+    Custom { width: f32, height: f32 },
+}
+
+impl PaperSize {
+    /// Names accepted by `from_name` (case insensitive)
+    pub const NAMES: &'static [&'static str] =
+        &["A0", "A1", "A2", "A3", "A4", "A5", "letter", "tabloid"];
+
+    /// Look up a standard paper size from its name ("A1", "letter"...)
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_ascii_lowercase().as_str() {
+            "a0" => Some(PaperSize::A0),
+            "a1" => Some(PaperSize::A1),
+            "a2" => Some(PaperSize::A2),
+            "a3" => Some(PaperSize::A3),
+            "a4" => Some(PaperSize::A4),
+            "a5" => Some(PaperSize::A5),
+            "letter" => Some(PaperSize::Letter),
+            "tabloid" => Some(PaperSize::Tabloid),
+            _ => None,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageOrientation {
+    Portrait,
+    Landscape,
+}
+
+impl PageOrientation {
+    /// Names accepted by `from_name` (case insensitive)
+    pub const NAMES: &'static [&'static str] = &["portrait", "landscape"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        return match name.to_ascii_lowercase().as_str() {
+            "portrait" => Some(PageOrientation::Portrait),
+            "landscape" => Some(PageOrientation::Landscape),
+            _ => None,
+        };
+    }
+}
+
+/// Appearance of the header bands starting each group of people
+#[derive(Clone)]
+pub struct SectionConfig {
+    pub header_height: f32,
+    pub font_size: f32,
+    pub text_color: PdfColor,
+    pub band_color: PdfColor,
+    pub group_colors: HashMap<String, PdfColor>, // Overrides band_color for specific groups
+}
+
+#[derive(Clone)]
 pub struct RenderConfig {
+    pub paper_size: PaperSize,
+    pub orientation: PageOrientation,
     pub page_hmargin: f32,
     pub page_vmargin: f32,
     pub inner_hmargin: f32, // This is the margin between cells
     pub inner_vmargin: f32,
     pub max_dpi: Option<u32>, // None means "no images downsizing" (max possible DPI)
+    pub fit_mode: FitMode,    // Overridden by "fit" and "fill" picture flags
+    pub fit_background: PdfColor, // Padding of fitted pictures
+    pub crop_strategy: CropStrategy,
+    pub resampling: Resampling,
+    pub jpeg_quality: u8,     // 1-100, for re-encoded images
+    pub jobs: Option<usize>, // Maximum number of images processed in parallel, None means "all cores"
+    pub min_photo_width: f32, // Solved grids use more pages rather than narrower photos (0 for no minimum)
+    pub labels: LabelConfig,
+    pub fonts: FontConfig,
+    pub header: Option<BandConfig>,
+    pub footer: Option<BandConfig>,
+    pub sections: Option<SectionConfig>, // None means "a plain grid, groups are not shown"
+    pub layout_mode: LayoutMode,
+    pub validation: Option<ValidationConfig>, // None means "no checks of pictures fitness for print"
+    pub debug: bool,                          // Draw layout information over the poster
+}
+
+impl RenderConfig {
+    /// Get the pdfium page size matching configured paper size and orientation
+    pub fn pdf_paper_size(&self) -> PdfPagePaperSize {
+        let size = match self.paper_size {
+            PaperSize::A0 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A0),
+            PaperSize::A1 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A1),
+            PaperSize::A2 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A2),
+            PaperSize::A3 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A3),
+            PaperSize::A4 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A4),
+            PaperSize::A5 => PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::A5),
+            PaperSize::Letter => {
+                PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::USLetterAnsiA)
+            }
+            PaperSize::Tabloid => {
+                PdfPagePaperSize::new_portrait(PdfPagePaperStandardSize::USLedgerTabloidAnsiB)
+            }
+            PaperSize::Custom { width, height } => {
+                PdfPagePaperSize::new_custom(PdfPoints::new(width), PdfPoints::new(height))
+            }
+        };
+
+        return match self.orientation {
+            PageOrientation::Portrait => size.portrait(),
+            PageOrientation::Landscape => size.landscape(),
+        };
+    }
+
+    /// Get the area of the header band of pages, below the top margin
+    pub fn header_area(&self, page_width: f32, page_height: f32) -> Option<Area> {
+        return self.header.as_ref().map(|header| Area {
+            left: self.page_hmargin,
+            bottom: page_height - self.page_vmargin - header.height,
+            width: page_width - self.page_hmargin * 2.,
+            height: header.height,
+        });
+    }
+
+    /// Get the area of the footer band of pages, above the bottom margin
+    pub fn footer_area(&self, page_width: f32) -> Option<Area> {
+        return self.footer.as_ref().map(|footer| Area {
+            left: self.page_hmargin,
+            bottom: self.page_vmargin,
+            width: page_width - self.page_hmargin * 2.,
+            height: footer.height,
+        });
+    }
 }
 
+/// One of several documents sharing the same layout, which only differ by their images encoding
+#[derive(Clone)]
+pub struct OutputVariant {
+    pub filename: String,
+    pub max_dpi: Option<u32>, // None means "no images downsizing" (max possible DPI)
+    pub jpeg_quality: u8,
+}
+
+/// Layout pictures in a grid, save the resulting PDF and report how it was made.
+/// Without an explicit `grid`, the one giving the largest photos is picked (see [layout::solve_grid]).
+/// Pictures which do not fit on the first page spill onto as many pages as needed.
 pub fn generate(
     pdfium: &Pdfium,
-    pictures: &Vec<(String, Vec<u8>)>,
-    nb_rows: i32,
-    nb_columns: i32,
+    pictures: &[Person],
+    grid: Option<Grid>,
     config: &RenderConfig,
-    filename: &String,
-    cache_dir: &std::path::Path,
-    title: &String,
-) -> Result<(), PdfiumError> {
-    let mut document = pdfium.create_new_pdf()?;
-
-    let mut page = document
-        .pages_mut()
-        .create_page_at_start(PdfPagePaperSize::a3().landscape())?;
+    filename: &str,
+    cache: &mut ImageCache,
+    title: &str,
+) -> Result<DocumentReport> {
+    let variant = OutputVariant {
+        filename: filename.to_string(),
+        max_dpi: config.max_dpi,
+        jpeg_quality: config.jpeg_quality,
+    };
+    let mut reports = generate_variants(pdfium, pictures, grid, config, &[variant], cache, title)?;
+    return Ok(reports.remove(0));
+}
 
-    let _font = document.fonts_mut().times_roman();
+/// Same as [generate], saving one PDF per variant (the `max_dpi` and `jpeg_quality` of `config`
+/// being overridden by the variant ones). Sources are decoded and cropped only once for all of them.
+pub fn generate_variants(
+    pdfium: &Pdfium,
+    pictures: &[Person],
+    grid: Option<Grid>,
+    config: &RenderConfig,
+    variants: &[OutputVariant],
+    cache: &mut ImageCache,
+    title: &str,
+) -> Result<Vec<DocumentReport>> {
+    let paper_size = config.pdf_paper_size();
 
     // Do calculations in PDF points (natural PDF unit)
-    let page_width = page.width().value;
-    let page_height = page.height().value;
+    let paper_width = paper_size.width().value;
+    let paper_height = paper_size.height().value;
+    let (page_width, page_height) = config.layout_mode.page_size(paper_width, paper_height);
+    let page_size =
+        PdfPagePaperSize::from_points(PdfPoints::new(page_width), PdfPoints::new(page_height));
+
+    // Content areas successively filled with pictures
+    config
+        .layout_mode
+        .check_frames(paper_width, paper_height, config)?;
+    let frames = |index| {
+        config
+            .layout_mode
+            .frame(index, paper_width, paper_height, config)
+    };
 
-    let cell_width: f32 = ((page_width - config.page_hmargin * 2.)
-        - (config.inner_hmargin * (nb_columns - 1) as f32))
-        / nb_columns as f32;
-    let cell_height: f32 = ((page_height - config.page_vmargin * 2.)
-        - (config.inner_vmargin * (nb_rows - 1) as f32))
-        / nb_rows as f32;
-    let cell_ratio = cell_height / cell_width;
+    // Get (upright) image dimensions
+    let sizes = pictures
+        .iter()
+        .map(|p| tools::upright_size(&p.data, &p.name))
+        .collect::<Result<Vec<(u32, u32)>>>()?;
+
+    // People sharing a group make a section (a single one when sections are disabled), laid out
+    // in `order`; people without a group come first, without a header band
+    let (groups, order) = group_runs(pictures, config);
+    let group_sizes: Vec<usize> = groups.iter().map(|(_, size)| *size).collect();
+    let header_heights: Vec<f32> = groups
+        .iter()
+        .map(|(group, _)| match (group, &config.sections) {
+            (Some(_), Some(sections)) => sections.header_height,
+            _ => 0.,
+        })
+        .collect();
+
+    // Labels get a band of their own below each cell
+    let subtitles = pictures.iter().any(|p| p.subtitle.is_some());
+    let label_height = config.labels.band_height(subtitles);
+    let grid = match grid {
+        Some(grid) => grid,
+        None => {
+            let photo_ratio = layout::typical_ratio(&sizes);
+            let frame = frames(0).1;
+            layout::solve_grid(
+                &group_sizes,
+                &frame,
+                config.layout_mode.frames_per_spread(),
+                config,
+                label_height,
+                &header_heights,
+                photo_ratio,
+            )
+            .ok_or(Error::NoRoomForGrid {
+                width: frame.width,
+                height: frame.height,
+            })?
+        }
+    };
 
-    //let font = document.fonts_mut().courier_bold();
-
-    let font = document
-        .fonts_mut()
-        .load_true_type_from_bytes(include_bytes!("../font/Chandler42 Regular.otf"), true)?;
-
-    // Place cells. Note that origin is at bottom left in PDF coordinates system
-    for (i, (name, pict_data)) in pictures.iter().enumerate() {
-        let row: i32 = i as i32 / nb_columns;
-        let column: i32 = i as i32 % nb_columns;
-        let cell_bottom: f32 = page_height
-            - (config.page_vmargin
-                + cell_height
-                + row as f32 * (cell_height + config.inner_vmargin));
-        let cell_left: f32 =
-            config.page_hmargin + column as f32 * (cell_width + config.inner_hmargin);
-
-        // Get image dimensions
-        let image_size = imagesize::blob_size(pict_data).expect("Could not guess image size");
-        let src_width = image_size.width as u32;
-        let src_height = image_size.height as u32;
-
-        // First compute the cropping required to make sure the imnage will fill cell completely
-        let crop = crop_to_fit_cell(src_width, src_height, cell_ratio);
-
-        // Once image is cropped, it trivially fits cell bounds
-        let image_width = cell_width;
-        let image_height = cell_height;
-
-        let dpi = tools::compute_dpi(src_width as usize, PdfPoints::new(image_width).to_cm());
-
-        let cached_name = get_cached_name(name, &crop, config);
-        let cached_path = cache_dir.join(tools::sanitize_filename(&cached_name));
-
-        // Try to load from cached bytes;
-        // otherwise perform image transforms and rencoding
-        let bytes = match tools::load_bytes_from_disk(&cached_path) {
-            Some(b) => {
-                println!("Using cached image for {name}");
-                b
-            }
-            None => {
-                // Actually decode JPEG data
-                let src_image = tools::decode_image(pict_data, name);
-
-                // Actually crop image data
-                let cropped = src_image.crop_imm(crop.x, crop.y, crop.width, crop.height);
-
-                // Resize the image if needed to target max DPI
-                let mut resized: DynamicImage = cropped;
-                match config.max_dpi {
-                    Some(max_dpi) => {
-                        if dpi > max_dpi {
-                            let dpi_ratio: f32 = max_dpi as f32 / dpi as f32;
-                            let dst_width = (src_width as f32 * dpi_ratio) as u32;
-                            let dst_height = (src_height as f32 * dpi_ratio) as u32;
-                            println!("Resolution of {name}: {dpi} DPI");
-                            println!("Need resizing to ({dst_width}, {dst_height}) to reach target resolution ({max_dpi} DPI)");
-
-                            // Resize image
-                            resized = resized.resize(dst_width, dst_height, FilterType::Lanczos3);
-                        }
-                    }
-                    None => { /* Nothing to do, image does not reach target DPI */ }
-                }
+    // Pictures which do not fit on a page spill onto the next one(s)
+    let page_layout = layout::flow_grid(
+        &group_sizes,
+        grid,
+        frames,
+        config,
+        label_height,
+        &header_heights,
+    );
 
-                // Get JPEG-encoded data
-                let bytes = tools::encode_to_jpeg(resized, name);
+    // Facing pages go by pairs
+    let nb_pages = match config.layout_mode {
+        LayoutMode::Spread {
+            facing_pages: true, ..
+        } => page_layout.nb_pages.next_multiple_of(2),
+        _ => page_layout.nb_pages,
+    };
 
-                // Cache final image data on disk
-                tools::save_bytes_to_disk(&cached_path, &bytes);
+    let cell_width = page_layout.cell_width;
+    let cell_height = page_layout.cell_height;
+    if cell_width <= 0. || cell_height <= 0. {
+        return Err(Error::EmptyCells {
+            rows: grid.rows,
+            columns: grid.columns,
+            width: cell_width,
+            height: cell_height,
+        });
+    }
+    let cell_ratio = cell_height / cell_width;
 
-                bytes
+    // Fonts are read (and checked) before any costly image processing
+    let fonts = Fonts::read(&config.fonts)?;
+    let fallbacks: Vec<bool> = pictures
+        .iter()
+        .map(|p| {
+            let subtitle = p.subtitle.as_deref().unwrap_or("");
+            fonts.needs_fallback(FontRole::Label, &tools::normalize_unicode(&p.name))
+                || fonts.needs_fallback(FontRole::Subtitle, &tools::normalize_unicode(subtitle))
+        })
+        .collect();
+    let fallback_names: Vec<&str> = pictures
+        .iter()
+        .zip(&fallbacks)
+        .filter(|(_, fallback)| **fallback)
+        .map(|(p, _)| p.name.as_str())
+        .collect();
+
+    // Characters no font can draw would show as blank boxes
+    let missing_glyphs: Vec<Vec<char>> = pictures
+        .iter()
+        .map(|p| {
+            let subtitle = p.subtitle.as_deref().unwrap_or("");
+            let mut missing =
+                fonts.missing_glyphs(FontRole::Label, &tools::normalize_unicode(&p.name));
+            for c in fonts.missing_glyphs(FontRole::Subtitle, &tools::normalize_unicode(subtitle)) {
+                if !missing.contains(&c) {
+                    missing.push(c);
+                }
             }
+            missing
+        })
+        .collect();
+    let missing_labels: Vec<(String, String)> = pictures
+        .iter()
+        .zip(&missing_glyphs)
+        .filter(|(_, missing)| !missing.is_empty())
+        .map(|(p, missing)| (p.name.clone(), missing.iter().collect()))
+        .collect();
+
+    // Same for titles: page title (replaced by the header, if any), section headers and bands
+    let date = decoration::generation_date();
+    let values = BandValues {
+        title,
+        date: &date,
+        page: nb_pages,
+        pages: nb_pages,
+    };
+    let mut titles: Vec<String> = Vec::new();
+    if config.header.is_none() {
+        titles.push(tools::normalize_unicode(title));
+    }
+    titles.extend(
+        groups
+            .iter()
+            .filter_map(|(group, _)| group.as_deref())
+            .map(tools::normalize_unicode),
+    );
+    for band in [&config.header, &config.footer].into_iter().flatten() {
+        titles.extend(band.texts(&values));
+    }
+    let missing_titles: Vec<(String, String)> = titles
+        .into_iter()
+        .filter_map(|text| {
+            let missing = fonts.missing_glyphs(FontRole::Title, &text);
+            if missing.is_empty() {
+                return None;
+            }
+            return Some((text, missing.into_iter().collect()));
+        })
+        .collect();
+
+    // Labels are warned about in the report of their picture, titles in the document one
+    if config.fonts.missing_glyphs == MissingGlyphs::Refuse
+        && !(missing_labels.is_empty() && missing_titles.is_empty())
+    {
+        return Err(Error::MissingGlyphs(
+            missing_labels.into_iter().chain(missing_titles).collect(),
+        ));
+    }
+    let mut document_warnings: Vec<String> = match config.fonts.missing_glyphs {
+        MissingGlyphs::Warn => missing_titles
+            .iter()
+            .map(|(text, missing)| format!("No font can draw some characters of {text}: {missing}"))
+            .collect(),
+        _ => Vec::new(),
+    };
+    if !fallback_names.is_empty() {
+        document_warnings.push(format!(
+            "Characters missing from the label fonts, drawn with a fallback font: {}",
+            fallback_names.join(", ")
+        ));
+    }
+
+    // First compute the cropping required to make sure each image will fill its cell completely
+    // (unless it is fitted), then process images ahead of the PDF assembly
+    let crops = imaging::compute_crops(pictures, &sizes, cell_ratio, config, cache);
+    let fitted: Vec<bool> = pictures
+        .iter()
+        .map(|p| FitMode::for_person(p, config.fit_mode) == FitMode::Fit)
+        .collect();
+    let mut jobs: Vec<ImageJob> = pictures
+        .iter()
+        .zip(&sizes)
+        .zip(crops)
+        .zip(&fitted)
+        .map(|(((person, size), crop), fitted)| ImageJob {
+            name: &person.name,
+            data: &person.data,
+            src_size: *size,
+            // Crops left to analysis are found once pictures are decoded (the default one is
+            // only a placeholder until then)
+            crop: crop
+                .unwrap_or_else(|| imaging::crop_to_fit_cell(size.0, size.1, cell_ratio, None)),
+            // Once image is cropped, it trivially fits cell bounds
+            image_width: if *fitted {
+                cell_width.min(cell_height * size.0 as f32 / size.1 as f32)
+            } else {
+                cell_width
+            },
+            focus: person.focus,
+            pending_crop: crop.is_none().then_some(cell_ratio),
+        })
+        .collect();
+
+    // Each source is decoded and cropped once for all variants
+    let variant_configs: Vec<RenderConfig> = variants
+        .iter()
+        .map(|variant| RenderConfig {
+            max_dpi: variant.max_dpi,
+            jpeg_quality: variant.jpeg_quality,
+            ..config.clone()
+        })
+        .collect();
+    let variant_images = imaging::prepare_images(&mut jobs, &variant_configs, cache)?;
+    cache.save_index()?;
+
+    // Header and footer logos are decoded once for all variants
+    let mut load_band = |band| Band::load(band, &mut document_warnings);
+    let header = config.header.as_ref().map(&mut load_band);
+    let footer = config.footer.as_ref().map(&mut load_band);
+
+    let mut reports = Vec::new();
+    for ((variant, config), images) in variants.iter().zip(&variant_configs).zip(&variant_images) {
+        let mut document = pdfium.create_new_pdf()?;
+        let mut report = DocumentReport {
+            filename: variant.filename.clone(),
+            max_dpi: variant.max_dpi,
+            jpeg_quality: variant.jpeg_quality,
+            grid_columns: grid.columns,
+            grid_rows: grid.rows,
+            pages: nb_pages,
+            pictures: 0,
+            cache_hits: 0,
+            encoded_bytes: 0,
+            min_output_dpi: 0,
+            warnings: 0,
+            document_warnings: document_warnings.clone(),
+            flagged: 0,
+            file_size: 0,
+            images: Vec::new(),
         };
 
-        // Center image horizontally, but keep it at cell bottom
-        let img_left = cell_left + (cell_width - image_width) / 2.0;
-        let img_bottom = cell_bottom;
+        let fonts = fonts.load(&mut document)?;
+        // The overlay font is only embedded when drawing the overlay
+        let debug_font = config.debug.then(|| document.fonts_mut().courier());
+
+        let mut pages = Vec::new();
+        for index in 0..nb_pages {
+            let mut page = add_page(&mut document, page_size, &fonts, title, config)?;
+            let values = BandValues {
+                title,
+                date: &date,
+                page: index + 1,
+                pages: nb_pages,
+            };
+            if let (Some(header), Some(area)) =
+                (&header, config.header_area(page_width, page_height))
+            {
+                header.draw(&document, &mut page, &fonts, &area, &values)?;
+            }
+            if let (Some(footer), Some(area)) = (&footer, config.footer_area(page_width)) {
+                footer.draw(&document, &mut page, &fonts, &area, &values)?;
+            }
+            pages.push(page);
+        }
+
+        if let Some(sections) = &config.sections {
+            for header in page_layout.headers.iter() {
+                let group = groups[header.index].0.as_deref().unwrap_or("");
+                draw_section_header(
+                    &document,
+                    &mut pages[header.page],
+                    &fonts,
+                    group,
+                    &header.area,
+                    sections,
+                )?;
+            }
+        }
+
+        // Place cells. Note that origin is at bottom left in PDF coordinates system
+        for cell in page_layout.cells.iter() {
+            let i = order[cell.index];
+            let name = &pictures[i].name;
+            let subtitle = pictures[i]
+                .subtitle
+                .as_ref()
+                .map(|s| tools::normalize_unicode(s));
+            let page = &mut pages[cell.page];
+            let cell_left = cell.area.left;
+            let cell_bottom = cell.area.bottom;
+
+            let (src_width, src_height) = sizes[i];
+            let crop = &jobs[i].crop;
+            let bytes = &images[i].bytes;
+            let mut image_report = ImageReport::new(
+                &jobs[i],
+                &pictures[i].file_name,
+                cell.page,
+                &images[i],
+                config.max_dpi,
+            );
+            if config.fonts.missing_glyphs != MissingGlyphs::Ignore && !missing_glyphs[i].is_empty()
+            {
+                let missing: String = missing_glyphs[i].iter().collect();
+                image_report.warnings.push(format!(
+                    "No font can draw some characters of the label: {missing}"
+                ));
+                image_report.missing_glyphs = missing_glyphs[i].clone();
+            }
+            if fallbacks[i] {
+                image_report
+                    .warnings
+                    .push("Label partly drawn with a fallback font".to_string());
+            }
 
-        // Build a PDF image object with DCTDecode (JPEG-encoded) data
-        let mut image_object =
-            PdfPageImageObject::new_from_jpeg_reader(&document, Cursor::new(&bytes))?;
+            let (image_width, image_height) = if fitted[i] {
+                let width = jobs[i].image_width;
+                (width, width * src_height as f32 / src_width as f32)
+            } else {
+                (cell_width, cell_height)
+            };
+
+            // Center image horizontally, but keep it at cell bottom (fitted ones are centred,
+            // over a padding of the background color)
+            let img_left = cell_left + (cell_width - image_width) / 2.0;
+            let img_bottom = cell_bottom + (cell_height - image_height) / 2.0;
+            if fitted[i] {
+                page.objects_mut().create_path_object_rect(
+                    PdfRect::new(
+                        PdfPoints::new(cell_bottom),
+                        PdfPoints::new(cell_left),
+                        PdfPoints::new(cell.area.top()),
+                        PdfPoints::new(cell.area.right()),
+                    ),
+                    None,
+                    None,
+                    Some(config.fit_background),
+                )?;
+            }
 
-        // Expected transformations order in PDF is "scaling, then rotation, then translation"
-        // "The returned page object will have its width and height both set to 1.0 points"
-        image_object.scale(image_width, image_height)?;
-        image_object.translate(PdfPoints::new(img_left), PdfPoints::new(img_bottom))?;
-        page.objects_mut().add_image_object(image_object)?;
+            // Build a PDF image object with DCTDecode (JPEG-encoded) data
+            let mut image_object =
+                PdfPageImageObject::new_from_jpeg_reader(&document, Cursor::new(bytes))?;
+
+            // Expected transformations order in PDF is "scaling, then rotation, then translation"
+            // "The returned page object will have its width and height both set to 1.0 points"
+            image_object.scale(image_width, image_height)?;
+            image_object.translate(PdfPoints::new(img_left), PdfPoints::new(img_bottom))?;
+            page.objects_mut().add_image_object(image_object)?;
+
+            draw_label(
+                &document,
+                page,
+                &fonts,
+                &tools::normalize_unicode(name),
+                subtitle.as_deref(),
+                &cell.area,
+                &config.labels,
+            )?;
+
+            if let Some(debug_font) = debug_font {
+                // The whole source image, as it would extend beyond the cell before cropping
+                let scale = image_width / crop.width as f32;
+                let source_top = img_bottom + image_height + crop.y as f32 * scale;
+                let source_area = Area {
+                    left: img_left - crop.x as f32 * scale,
+                    bottom: source_top - src_height as f32 * scale,
+                    width: src_width as f32 * scale,
+                    height: src_height as f32 * scale,
+                };
+                let label_band = Area {
+                    left: cell_left,
+                    bottom: cell_bottom - label_height,
+                    width: cell_width,
+                    height: label_height,
+                };
+
+                // Resolution actually achieved by the embedded image
+                let achieved_dpi = image_report.output_dpi;
+
+                overlay::draw_debug_rect(page, overlay::CROP_COLOR, &source_area, true)?;
+                overlay::draw_debug_rect(page, overlay::CELL_COLOR, &cell.area, false)?;
+                overlay::fill_debug_rect(page, overlay::LABEL_BAND_COLOR, &label_band)?;
+                overlay::draw_debug_text(
+                    &document,
+                    page,
+                    debug_font,
+                    format!("#{} {achieved_dpi} DPI", cell.index + 1),
+                    cell_left + 1.,
+                    cell.area.top() - 1.,
+                )?;
+
+                let marked = config
+                    .validation
+                    .as_ref()
+                    .is_some_and(|v| v.mark_in_overlay);
+                if marked && !image_report.issues.is_empty() {
+                    let codes: Vec<&str> = image_report.issues.iter().map(|i| i.code()).collect();
+                    overlay::draw_issue_marker(page, &cell.area)?;
+                    overlay::draw_debug_text(
+                        &document,
+                        page,
+                        debug_font,
+                        codes.join(" "),
+                        cell_left + 1.,
+                        cell.area.top() - 6.,
+                    )?;
+                }
+            }
 
-        // Emit the label
-        let font_size = 5.0;
+            report.images.push(image_report);
+        }
 
-        let mut text_object = PdfPageTextObject::new(
-            &document,
-            tools::normalize_unicode(name),
-            font,
-            PdfPoints::new(font_size),
-        )?;
+        if config.debug {
+            for (page_index, page) in pages.iter_mut().enumerate() {
+                draw_page_overlay(page, page_index, frames, config, page_width)?;
+            }
+        }
 
-        //object.set_fill_color(PdfColor::new(random(), random(), random(), 255))?;
+        document.save_to_file(&variant.filename)?;
 
-        let text_bounds = text_object.bounds().unwrap();
-        let text_width = text_bounds.x3.value - text_bounds.x1.value;
-        let hspace = cell_width - text_width;
+        report.file_size = std::fs::metadata(&variant.filename)
+            .map_err(|e| Error::io(&variant.filename, e))?
+            .len();
+        report.update_totals();
+        reports.push(report);
+    }
 
-        text_object.translate(
-            PdfPoints::new(img_left + hspace / 2.),
-            PdfPoints::new(cell_bottom - config.inner_vmargin / 2.),
-        )?;
+    return Ok(reports);
+}
 
-        // Add the object to the page, triggering content regeneration.
-        page.objects_mut().add_text_object(text_object)?;
+/// Gather people by group: return the groups with their size, and the order in which to lay
+/// pictures out (indices in `pictures`). People without a group come first, then groups in order
+/// of first appearance, people keeping their relative order within a group.
+/// Without sections, everybody belongs to a single anonymous group.
+fn group_runs(
+    pictures: &[Person],
+    config: &RenderConfig,
+) -> (Vec<(Option<String>, usize)>, Vec<usize>) {
+    if config.sections.is_none() {
+        return (vec![(None, pictures.len())], (0..pictures.len()).collect());
     }
 
-    // Add page title (based on the file name)
-    let title_font_size = 10.0;
-    let mut title_object = PdfPageTextObject::new(
-        &document,
-        tools::normalize_unicode(title),
-        font,
-        PdfPoints::new(title_font_size),
-    )?;
+    let mut buckets: Vec<(Option<String>, Vec<usize>)> = vec![(None, Vec::new())];
+    for (i, person) in pictures.iter().enumerate() {
+        match buckets.iter_mut().find(|(group, _)| *group == person.group) {
+            Some((_, members)) => members.push(i),
+            None => buckets.push((person.group.clone(), vec![i])),
+        }
+    }
+    buckets.retain(|(_, members)| !members.is_empty());
+
+    let groups = buckets
+        .iter()
+        .map(|(group, members)| (group.clone(), members.len()))
+        .collect();
+    let order = buckets
+        .into_iter()
+        .flat_map(|(_, members)| members)
+        .collect();
+    return (groups, order);
+}
 
-    let text_bounds = title_object.bounds().unwrap();
-    let text_width = text_bounds.x3.value - text_bounds.x1.value;
+/// Draw a label in the band below a cell, shrunk, wrapped or cut to fit the cell width,
+/// with the subtitle (if any) on a line of its own below the name
+fn draw_label<'a>(
+    document: &PdfDocument<'a>,
+    page: &mut PdfPage<'a>,
+    fonts: &LoadedFonts,
+    text: &str,
+    subtitle: Option<&str>,
+    cell: &Area,
+    labels: &LabelConfig,
+) -> Result<()> {
+    let draw_line = |page: &mut PdfPage<'a>, line: &str, style: &TextStyle, baseline: f32| {
+        let text_width = fonts.measure(document, style.role, line, style.font_size)?;
+        let left = cell.left + (cell.width - text_width) / 2.;
+        return fonts.draw(document, page, line, style, left, baseline);
+    };
 
-    title_object.translate(
-        PdfPoints::new(page_width - text_width - config.page_hmargin),
-        PdfPoints::new(page_height - config.page_vmargin * 0.6),
-    )?;
+    // Name lines are centred vertically in their part of the band (baselines sit below the top
+    // of capitals)
+    let measure = |text: &str, font_size: f32| -> Result<f32> {
+        return fonts.measure(document, FontRole::Label, text, font_size);
+    };
+    let layout = label::layout_label(text, cell.width, labels, measure)?;
+    let style = TextStyle {
+        role: FontRole::Label,
+        font_size: layout.font_size,
+        color: PdfColor::BLACK,
+    };
+    let line_height = layout.font_size * labels.line_spacing;
+    let block_height = layout.font_size + line_height * (layout.lines.len() - 1) as f32;
+    let name_height = labels.name_height();
+    let first_baseline = cell.bottom - (name_height - block_height) / 2. - layout.font_size * 0.75;
+    for (n, line) in layout.lines.iter().enumerate() {
+        draw_line(page, line, &style, first_baseline - n as f32 * line_height)?;
+    }
 
-    page.objects_mut().add_text_object(title_object)?;
+    if let Some(subtitle) = subtitle {
+        let sub = &labels.subtitle;
+        let measure = |text: &str, font_size: f32| -> Result<f32> {
+            return fonts.measure(document, FontRole::Subtitle, text, font_size);
+        };
+        let layout = label::layout_line(
+            subtitle,
+            cell.width,
+            sub.font_size,
+            sub.min_font_size,
+            measure,
+        )?;
+        let style = TextStyle {
+            role: FontRole::Subtitle,
+            font_size: layout.font_size,
+            color: sub.color,
+        };
+        let sub_height = labels.band_height(true) - name_height;
+        let baseline = cell.bottom
+            - name_height
+            - (sub_height - layout.font_size) / 2.
+            - layout.font_size * 0.75;
+        for line in layout.lines.iter() {
+            draw_line(page, line, &style, baseline)?;
+        }
+    }
+    return Ok(());
+}
 
-    document.save_to_file(&filename)?;
+/// Draw the band announcing a group, with the group name on its left
+fn draw_section_header<'a>(
+    document: &PdfDocument<'a>,
+    page: &mut PdfPage<'a>,
+    fonts: &LoadedFonts,
+    group: &str,
+    area: &Area,
+    sections: &SectionConfig,
+) -> Result<()> {
+    let band_color = sections
+        .group_colors
+        .get(group)
+        .copied()
+        .unwrap_or(sections.band_color);
+
+    page.objects_mut().create_path_object_rect(
+        PdfRect::new(
+            PdfPoints::new(area.bottom),
+            PdfPoints::new(area.left),
+            PdfPoints::new(area.top()),
+            PdfPoints::new(area.right()),
+        ),
+        None,
+        None,
+        Some(band_color),
+    )?;
 
-    println!("Done.");
-    Ok(())
+    // Vertically center the group name in the band (capitals are about 0.7 of the size high)
+    let style = TextStyle {
+        role: FontRole::Title,
+        font_size: sections.font_size,
+        color: sections.text_color,
+    };
+    return fonts.draw(
+        document,
+        page,
+        &tools::normalize_unicode(group),
+        &style,
+        area.left + sections.font_size / 2.,
+        area.bottom + (area.height - sections.font_size * 0.7) / 2.,
+    );
 }
 
-/// Compute how to crop image to make sure it will fill cell completely
-fn crop_to_fit_cell(src_width: u32, src_height: u32, cell_ratio: f32) -> Rectangle {
-    let x: u32;
-    let y: u32;
-    let width: u32;
-    let height: u32;
-    let image_ratio = src_height as f32 / src_width as f32;
-    if cell_ratio > image_ratio {
-        // Cell is proportionally taller than image => need to crop image left and/or right
-        height = src_height as u32;
-        width = (height as f32 / cell_ratio) as u32;
-        x = (src_width as u32 - width) / 2;
-        y = 0;
-    } else {
-        // Need to crop image top and/or bottom
-        // To respect faces, crop bottom (less chance to cut top of hair)
-        width = src_width as u32;
-        height = (width as f32 * cell_ratio) as u32;
-        x = 0;
-        y = 0; // (src_height as u32 - height) / 2;
-    }
-    return Rectangle {
-        x,
-        y,
-        width,
-        height,
+/// Show the content areas of a page (i.e. its margins), and the fold of single sheet spreads
+fn draw_page_overlay(
+    page: &mut PdfPage,
+    page_index: usize,
+    frames: impl Fn(usize) -> (usize, Area),
+    config: &RenderConfig,
+    page_width: f32,
+) -> Result<()> {
+    let page_height = page.height().value;
+
+    let frames_per_page = match config.layout_mode {
+        LayoutMode::Spread {
+            facing_pages: false,
+            ..
+        } => 2,
+        _ => 1,
     };
-}
+    for frame_index in page_index * frames_per_page..(page_index + 1) * frames_per_page {
+        let (_, frame) = frames(frame_index);
+        overlay::draw_debug_rect(page, overlay::MARGIN_COLOR, &frame, true)?;
+    }
+    for band in [
+        config.header_area(page_width, page_height),
+        config.footer_area(page_width),
+    ]
+    .iter()
+    .flatten()
+    {
+        overlay::draw_debug_rect(page, overlay::MARGIN_COLOR, band, true)?;
+    }
 
-struct Rectangle {
-    pub x: u32,
-    pub y: u32,
-    pub width: u32,
-    pub height: u32,
+    if frames_per_page == 2 {
+        let page_center_x = page_width / 2.;
+        overlay::draw_debug_line(
+            page,
+            overlay::MARGIN_COLOR,
+            page_center_x,
+            0.,
+            page_center_x,
+            page_height,
+        )?;
+    }
+
+    overlay::flush_debug_lines(page)?;
+    return Ok(());
 }
 
-fn get_cached_name(name: &String, crop: &Rectangle, config: &RenderConfig) -> String {
-    return format!(
-        "{name}-cropped({},{},{},{})-dpi({}).jpg",
-        crop.x,
-        crop.y,
-        crop.width,
-        crop.height,
-        config
-            .max_dpi
-            .map(|n| u32::to_string(&n))
-            .unwrap_or(String::from("native"))
-    );
+/// Append a new page to the document, decorated with the poster title (unless the header shows it)
+fn add_page<'a>(
+    document: &mut PdfDocument<'a>,
+    paper_size: PdfPagePaperSize,
+    fonts: &LoadedFonts,
+    title: &str,
+    config: &RenderConfig,
+) -> Result<PdfPage<'a>> {
+    let mut page = document.pages_mut().create_page_at_end(paper_size)?;
+    let page_width = page.width().value;
+    let page_height = page.height().value;
+
+    if config.header.is_some() {
+        return Ok(page);
+    }
+
+    // Add page title (based on the file name)
+    let style = TextStyle {
+        role: FontRole::Title,
+        font_size: 10.,
+        color: PdfColor::BLACK,
+    };
+    let title = tools::normalize_unicode(title);
+    let text_width = fonts.measure(document, style.role, &title, style.font_size)?;
+    fonts.draw(
+        document,
+        &mut page,
+        &title,
+        &style,
+        page_width - text_width - config.page_hmargin,
+        page_height - config.page_vmargin * 0.6,
+    )?;
+
+    return Ok(page);
 }
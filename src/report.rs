@@ -0,0 +1,139 @@
+use crate::error::Result;
+use crate::imaging::{self, ImageJob, PreparedImage, Rectangle};
+use crate::tools;
+use crate::validation::Issue;
+use pdfium_render::prelude::PdfPoints;
+use serde::Serialize;
+use std::path::Path;
+
+/// What happened to a picture while producing a document
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageReport {
+    pub name: String,
+    pub file_name: String,
+    pub page: usize,
+    /// Upright source dimensions, in pixels
+    pub source_width: u32,
+    pub source_height: u32,
+    /// Part of the (upright) source which is shown
+    pub crop: Rectangle,
+    /// Resolution of the cropped source once placed
+    pub source_dpi: u32,
+    /// Resolution of the embedded image
+    pub output_dpi: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    pub cache_hit: bool,
+    pub encoded_bytes: usize,
+    pub warnings: Vec<String>,
+    /// Characters of the label (and subtitle) which no font can draw
+    pub missing_glyphs: Vec<char>,
+    /// Problems found by the validation pass, if enabled
+    pub issues: Vec<Issue>,
+}
+
+impl ImageReport {
+    /// Report how the image of a `job` was `prepared` for a document (aiming at `max_dpi`)
+    pub fn new(
+        job: &ImageJob,
+        file_name: &str,
+        page: usize,
+        prepared: &PreparedImage,
+        max_dpi: Option<u32>,
+    ) -> Self {
+        let (output_width, output_height) = imagesize::blob_size(&prepared.bytes)
+            .map(|size| (size.width as u32, size.height as u32))
+            .unwrap_or((0, 0));
+        let source_dpi = imaging::effective_dpi(&job.crop, job.image_width);
+
+        let mut warnings = Vec::new();
+        if let Some(max_dpi) = max_dpi {
+            if source_dpi < max_dpi {
+                warnings.push(format!(
+                    "Only {source_dpi} DPI, below the {max_dpi} DPI target"
+                ));
+            }
+        }
+
+        return ImageReport {
+            name: job.name.to_string(),
+            file_name: file_name.to_string(),
+            page,
+            source_width: job.src_size.0,
+            source_height: job.src_size.1,
+            crop: job.crop,
+            source_dpi,
+            output_dpi: tools::compute_dpi(
+                output_width as usize,
+                PdfPoints::new(job.image_width).to_cm(),
+            ),
+            output_width,
+            output_height,
+            cache_hit: prepared.cache_hit,
+            encoded_bytes: prepared.bytes.len(),
+            warnings,
+            missing_glyphs: Vec::new(),
+            issues: prepared.issues.clone(),
+        };
+    }
+}
+
+/// What went into a generated document
+#[derive(Clone, Debug, Serialize)]
+pub struct DocumentReport {
+    pub filename: String,
+    pub max_dpi: Option<u32>,
+    pub jpeg_quality: u8,
+    pub grid_columns: i32,
+    pub grid_rows: i32,
+    pub pages: usize,
+    pub pictures: usize,
+    pub cache_hits: usize,
+    /// Sum of the embedded images sizes, in bytes
+    pub encoded_bytes: usize,
+    pub min_output_dpi: u32,
+    pub warnings: usize,
+    /// Warnings about the document itself rather than a picture (counted in `warnings`)
+    pub document_warnings: Vec<String>,
+    /// Number of pictures with validation issues
+    pub flagged: usize,
+    /// Size of the saved PDF, in bytes
+    pub file_size: u64,
+    pub images: Vec<ImageReport>,
+}
+
+impl DocumentReport {
+    /// Compute totals once all images are reported
+    pub fn update_totals(&mut self) {
+        self.pictures = self.images.len();
+        self.cache_hits = self.images.iter().filter(|i| i.cache_hit).count();
+        self.encoded_bytes = self.images.iter().map(|i| i.encoded_bytes).sum();
+        self.min_output_dpi = self.images.iter().map(|i| i.output_dpi).min().unwrap_or(0);
+        self.warnings = self.images.iter().map(|i| i.warnings.len()).sum::<usize>()
+            + self.document_warnings.len();
+        self.flagged = self.images.iter().filter(|i| !i.issues.is_empty()).count();
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string_pretty(self).expect("Report is always serializable");
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        return tools::save_bytes_to_disk(path, self.to_json().as_bytes());
+    }
+
+    /// One line summary, for the console
+    pub fn summary(&self) -> String {
+        return format!(
+            "{}: {} page(s), {} pictures ({} from cache), {} DPI min, {} warning(s), {} flagged, {} KiB",
+            self.filename,
+            self.pages,
+            self.pictures,
+            self.cache_hits,
+            self.min_output_dpi,
+            self.warnings,
+            self.flagged,
+            self.file_size / 1024
+        );
+    }
+}
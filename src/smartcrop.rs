@@ -0,0 +1,180 @@
+use crate::imaging::{self, Rectangle};
+use image::{DynamicImage, GrayImage, RgbImage};
+
+/// Pictures are analyzed once scaled down to fit this size
+const ANALYSIS_SIZE: u32 = 256;
+
+/// Side of the blocks on which entropy is measured, in analysis pixels
+const ENTROPY_BLOCK: u32 = 8;
+
+/// Maximum number of candidate windows tried along the cropped axis
+const MAX_CANDIDATES: u32 = 64;
+
+// Relative weights of the features (skin matters most: people are what we show)
+const EDGE_WEIGHT: f32 = 0.2;
+const SKIN_WEIGHT: f32 = 1.8;
+const ENTROPY_WEIGHT: f32 = 0.3;
+
+/// Direction of a typical skin color in the RGB space
+const SKIN_COLOR: [f32; 3] = [0.78, 0.57, 0.44];
+const SKIN_THRESHOLD: f32 = 0.8;
+const SKIN_MIN_BRIGHTNESS: f32 = 0.2;
+
+/// Find the window of the `cell_ratio` (height / width) which best shows the interesting part of
+/// an (upright) image, in the spirit of smartcrop.js: each pixel gets a score from edges,
+/// skin tones and local entropy, and windows are compared by their (centre weighted) score.
+/// Windows are as large as possible, so that no resolution is lost to zooming.
+pub fn smart_crop(image: &DynamicImage, cell_ratio: f32) -> Rectangle {
+    let (src_width, src_height) = (image.width(), image.height());
+    let full = imaging::crop_to_fit_cell(src_width, src_height, cell_ratio, None);
+    if full.width == src_width && full.height == src_height {
+        return full;
+    }
+
+    let small = if src_width > ANALYSIS_SIZE || src_height > ANALYSIS_SIZE {
+        image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE)
+    } else {
+        image.clone()
+    };
+    let scores = score_map(&small.to_rgb8());
+    let scale = small.width() as f32 / src_width as f32;
+
+    // The window only moves along the cropped axis
+    let horizontal = full.width < src_width;
+    let (window_width, window_height) = (
+        ((full.width as f32 * scale).round() as u32).clamp(1, small.width()),
+        ((full.height as f32 * scale).round() as u32).clamp(1, small.height()),
+    );
+    let range = if horizontal {
+        small.width() - window_width
+    } else {
+        small.height() - window_height
+    };
+    let step = range.div_ceil(MAX_CANDIDATES).max(1);
+
+    // The far end is always a candidate, even when the range is not a multiple of the step
+    let mut offsets: Vec<u32> = (0..range).step_by(step as usize).collect();
+    offsets.push(range);
+
+    let mut best = (0, f32::MIN);
+    for offset in offsets {
+        let (left, top) = if horizontal { (offset, 0) } else { (0, offset) };
+        let score = window_score(
+            &scores,
+            small.width(),
+            left,
+            top,
+            window_width,
+            window_height,
+        );
+        // Strictly better only: ties keep the leftmost / topmost window
+        if score > best.1 {
+            best = (offset, score);
+        }
+    }
+
+    let offset = (best.0 as f32 / scale).round() as u32;
+    return if horizontal {
+        Rectangle {
+            x: offset.min(src_width - full.width),
+            ..full
+        }
+    } else {
+        Rectangle {
+            y: offset.min(src_height - full.height),
+            ..full
+        }
+    };
+}
+
+/// Score every pixel of the analysis image
+fn score_map(rgb: &RgbImage) -> Vec<f32> {
+    let (width, height) = rgb.dimensions();
+    let luma: GrayImage = DynamicImage::ImageRgb8(rgb.clone()).to_luma8();
+    let entropy = entropy_map(&luma);
+
+    let mut scores = vec![0.; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let at = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as f32;
+
+            // Edges: Laplacian magnitude (borders are left out)
+            let edge = if x > 0 && y > 0 && x < width - 1 && y < height - 1 {
+                let laplacian =
+                    4. * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1);
+                (laplacian.abs() / 255.).min(1.)
+            } else {
+                0.
+            };
+
+            let skin = skin_score(rgb.get_pixel(x, y).0, at(x, y) / 255.);
+            let block = (y / ENTROPY_BLOCK) * width.div_ceil(ENTROPY_BLOCK) + x / ENTROPY_BLOCK;
+
+            scores[(y * width + x) as usize] =
+                EDGE_WEIGHT * edge + SKIN_WEIGHT * skin + ENTROPY_WEIGHT * entropy[block as usize];
+        }
+    }
+    return scores;
+}
+
+/// How much a pixel looks like skin (0 to 1)
+fn skin_score(rgb: [u8; 3], brightness: f32) -> f32 {
+    if brightness < SKIN_MIN_BRIGHTNESS {
+        return 0.;
+    }
+    let [r, g, b] = rgb.map(|c| c as f32);
+    let norm = (r * r + g * g + b * b).sqrt().max(1.);
+    let distance = ((r / norm - SKIN_COLOR[0]).powi(2)
+        + (g / norm - SKIN_COLOR[1]).powi(2)
+        + (b / norm - SKIN_COLOR[2]).powi(2))
+    .sqrt();
+    let similarity = 1. - distance;
+    if similarity < SKIN_THRESHOLD {
+        return 0.;
+    }
+    return (similarity - SKIN_THRESHOLD) / (1. - SKIN_THRESHOLD);
+}
+
+/// Shannon entropy of the luminance of each block (normalized from 0 to 1), blocks row by row
+fn entropy_map(luma: &GrayImage) -> Vec<f32> {
+    let (width, height) = luma.dimensions();
+    let columns = width.div_ceil(ENTROPY_BLOCK);
+    let rows = height.div_ceil(ENTROPY_BLOCK);
+
+    // 16 bins histograms
+    let mut histograms = vec![[0u32; 16]; (columns * rows) as usize];
+    for (x, y, pixel) in luma.enumerate_pixels() {
+        let block = (y / ENTROPY_BLOCK) * columns + x / ENTROPY_BLOCK;
+        histograms[block as usize][(pixel.0[0] / 16) as usize] += 1;
+    }
+
+    return histograms
+        .iter()
+        .map(|histogram| {
+            let count: u32 = histogram.iter().sum();
+            let entropy: f32 = histogram
+                .iter()
+                .filter(|n| **n > 0)
+                .map(|n| {
+                    let p = *n as f32 / count as f32;
+                    -p * p.log2()
+                })
+                .sum();
+            entropy / 4. // log2(16)
+        })
+        .collect();
+}
+
+/// Average score of a window, pixels close to its centre counting more
+fn window_score(scores: &[f32], stride: u32, left: u32, top: u32, width: u32, height: u32) -> f32 {
+    let mut total = 0.;
+    for y in 0..height {
+        let v = (2. * y as f32 + 1.) / height as f32 - 1.;
+        for x in 0..width {
+            let u = (2. * x as f32 + 1.) / width as f32 - 1.;
+            let importance = (1. - 0.5 * u * u) * (1. - 0.5 * v * v);
+            total += importance * scores[((top + y) * stride + left + x) as usize];
+        }
+    }
+    return total / (width * height) as f32;
+}
@@ -0,0 +1,161 @@
+use crate::codepage::LegacyEncoding;
+use crate::error::Result;
+use crate::manifest::Manifest;
+use crate::person::Person;
+use crate::tools;
+use std::path::{Path, PathBuf};
+
+/// Where pictures are read from
+pub enum PictureSource {
+    /// A zip archive, with the encoding of its non UTF-8 entry names
+    Archive(PathBuf, LegacyEncoding),
+    /// A directory, walked recursively
+    Directory(PathBuf),
+}
+
+impl PictureSource {
+    /// Pick the source kind matching what lies at `path`
+    pub fn from_path(path: &Path) -> Self {
+        if path.is_dir() {
+            return PictureSource::Directory(path.to_path_buf());
+        }
+        return PictureSource::Archive(path.to_path_buf(), LegacyEncoding::default());
+    }
+
+    /// Override the encoding used for legacy archive entry names (no effect on directories)
+    pub fn with_legacy_encoding(self, legacy_encoding: LegacyEncoding) -> Self {
+        return match self {
+            PictureSource::Archive(path, _) => PictureSource::Archive(path, legacy_encoding),
+            directory => directory,
+        };
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            PictureSource::Archive(path, _) | PictureSource::Directory(path) => path,
+        }
+    }
+
+    /// A human readable name for this source (suitable as a poster title)
+    pub fn title(&self) -> String {
+        let path = self.path();
+        let name = match self {
+            PictureSource::Archive(..) => path.file_stem(),
+            PictureSource::Directory(_) => path.file_name(),
+        };
+        return name
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string());
+    }
+
+    /// Load all pictures, and describe the people they show.
+    /// A manifest shipped along with the pictures (or named after the source) overrides
+    /// what is derived from the file names, and picture sidecars override the manifest.
+    pub fn load(&self) -> Result<Vec<Person>> {
+        let (sidecars, mut files): (Vec<_>, Vec<_>) = self
+            .load_files()?
+            .into_iter()
+            .partition(|(n, _)| Manifest::sidecar_target(n).is_some());
+
+        let manifest = match files
+            .iter()
+            .position(|(n, _)| Manifest::is_manifest_name(n))
+        {
+            Some(i) => {
+                let (name, bytes) = files.remove(i);
+                Some(Manifest::parse(&name, &bytes)?)
+            }
+            None => match Manifest::find_next_to(self.path()) {
+                Some(path) => Some(Manifest::load(&path)?),
+                None => None,
+            },
+        };
+
+        let mut people: Vec<Person> = files
+            .into_iter()
+            .map(|(name, bytes)| Person::from_file(name, bytes))
+            .collect();
+
+        if let Some(manifest) = manifest {
+            for person in people.iter_mut() {
+                manifest.apply(person);
+            }
+
+            for entry in manifest.entries.iter() {
+                let file_name = tools::normalize_unicode(&entry.file);
+                if !people
+                    .iter()
+                    .any(|p| tools::normalize_unicode(&p.file_name) == file_name)
+                {
+                    println!("Manifest entry {} matches no picture", entry.file);
+                }
+            }
+        }
+
+        for (name, bytes) in sidecars {
+            let target = tools::normalize_unicode(Manifest::sidecar_target(&name).unwrap_or(""));
+            let entry = Manifest::parse_sidecar(&name, &bytes)?;
+            match people
+                .iter_mut()
+                .find(|p| tools::normalize_unicode(&p.file_name) == target)
+            {
+                Some(person) => entry.apply(person),
+                None => println!("Sidecar {name} matches no picture"),
+            }
+        }
+
+        return Ok(people);
+    }
+
+    /// Load all files, return (name, bytes) tuples
+    pub fn load_files(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        match self {
+            PictureSource::Archive(path, legacy_encoding) => {
+                tools::load_images_from_archive(path, *legacy_encoding)
+            }
+            PictureSource::Directory(path) => tools::load_images_from_directory(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    /// Write an archive of (name, content) entries in the temporary directory
+    fn write_archive(file_name: &str, entries: &[(&str, &[u8])]) -> PathBuf {
+        let path = std::env::temp_dir().join(file_name);
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for (name, content) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap();
+        return path;
+    }
+
+    #[test]
+    fn archive_manifest_and_sidecars_apply() {
+        let path = write_archive(
+            "trombinoscope-test-manifest.zip",
+            &[
+                ("A_Jean.jpg", b"not decoded while loading"),
+                ("manifest.csv", b"file,subtitle\nA_Jean.jpg,Captain\n"),
+                ("A_Jean.jpg.json", br#"{"focus_x": 0.2, "focus_y": 0.3}"#),
+                ("notes.txt", b"not a picture"),
+            ],
+        );
+        let people = PictureSource::from_path(&path).load().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(people.len(), 1);
+        assert_eq!(people[0].name, "Jean");
+        assert_eq!(people[0].subtitle.as_deref(), Some("Captain"));
+        let focus = people[0].focus.unwrap();
+        assert_eq!((focus.x, focus.y), (0.2, 0.3));
+    }
+}
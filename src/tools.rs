@@ -1,33 +1,53 @@
+use crate::codepage::LegacyEncoding;
+use crate::error::{Error, Result};
+use crate::manifest::Manifest;
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
 use image::DynamicImage;
-use image::ImageReader;
+use image::{ImageDecoder, ImageFormat, ImageReader};
 use std::fs;
-use std::io;
 use std::io::Write;
 use std::io::{BufReader, Cursor, Read};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use unicode_normalization::UnicodeNormalization;
 
-/// Load an archive, return (name, bytes) tuples
-pub fn load_images_from_archive(archive_path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+/// Load an archive, return (name, bytes) tuples.
+/// Entry names not flagged as UTF-8 are decoded with `legacy_encoding`.
+pub fn load_images_from_archive(
+    archive_path: &Path,
+    legacy_encoding: LegacyEncoding,
+) -> Result<Vec<(String, Vec<u8>)>> {
     // Read archive contents
-    let file = fs::File::open(archive_path).unwrap();
+    let file = fs::File::open(archive_path).map_err(|e| Error::io(archive_path, e))?;
     let reader = BufReader::new(file);
 
-    let mut archive = zip::ZipArchive::new(reader).unwrap();
+    let mut archive = zip::ZipArchive::new(reader)?;
 
     let mut files: Vec<(String, Vec<u8>)> = Vec::new();
 
     for i in 0..archive.len() {
-        let mut file = archive.by_index(i).unwrap();
-        if let None = file.enclosed_name() {
+        let mut file = archive.by_index(i)?;
+        if file.enclosed_name().is_none() {
             println!("Entry {} has a suspicious path", file.name());
             continue;
         }
 
-        let filepath = String::from_utf8(file.name_raw().to_vec()).unwrap();
-        let filename = String::from(Path::new(&filepath).file_name().unwrap().to_str().unwrap());
-
-        if file.is_dir() || filepath.starts_with("__MACOSX") || (filename == ".DS_Store") {
+        // The zip crate only exposes raw bytes verbatim for UTF-8 flagged (or ASCII) names
+        let filepath = if file.name().as_bytes() == file.name_raw() {
+            file.name().to_string()
+        } else {
+            legacy_encoding.decode_name(file.name_raw())
+        };
+        let filename = match Path::new(&filepath).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+
+        if file.is_dir() || is_junk_entry(Path::new(&filepath)) {
+            continue;
+        }
+        if !is_picture_source_file(Path::new(&filepath)) {
+            println!("Skipping entry {filepath}: not a supported image");
             continue;
         }
 
@@ -40,8 +60,15 @@ pub fn load_images_from_archive(archive_path: &Path) -> io::Result<Vec<(String,
 
         // Read whole file contents
         let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        assert!(file.size() == buffer.len().try_into().unwrap());
+        file.read_to_end(&mut buffer)
+            .map_err(|e| Error::io(archive_path.join(&filepath), e))?;
+        if file.size() != buffer.len() as u64 {
+            return Err(Error::TruncatedEntry {
+                name: filepath,
+                size: file.size(),
+                read: buffer.len() as u64,
+            });
+        }
 
         files.push((filename, buffer));
     }
@@ -49,66 +76,170 @@ pub fn load_images_from_archive(archive_path: &Path) -> io::Result<Vec<(String,
     return Ok(files);
 }
 
+/// Load a directory tree (recursively), return (name, bytes) tuples sorted by relative path
+pub fn load_images_from_directory(dir_path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut paths = Vec::new();
+    collect_files(dir_path, dir_path, &mut paths)?;
+    paths.sort();
+
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    for path in paths {
+        let filename = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if !is_picture_source_file(&path) {
+            println!("Skipping file {}: not a supported image", path.display());
+            continue;
+        }
+
+        let buffer = fs::read(&path).map_err(|e| Error::io(&path, e))?;
+        println!(
+            "File {} has name \"{}\" ({} bytes)",
+            path.display(),
+            filename,
+            buffer.len()
+        );
+
+        files.push((filename, buffer));
+    }
+
+    return Ok(files);
+}
+
+/// Recursively gather the (non junk) files below `dir`
+fn collect_files(root: &Path, dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).map_err(|e| Error::io(dir, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| Error::io(dir, e))?.path();
+        if is_junk_entry(path.strip_prefix(root).unwrap_or(&path)) {
+            continue;
+        }
+
+        if path.is_dir() {
+            collect_files(root, &path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    return Ok(());
+}
+
+/// Tell if a (relative) path is an OS artifact or a hidden file, not a picture
+fn is_junk_entry(path: &Path) -> bool {
+    return path.components().any(|c| match c {
+        Component::Normal(name) => {
+            let name = name.to_string_lossy();
+            name == "__MACOSX" || name.starts_with('.')
+        }
+        _ => false,
+    });
+}
+
+/// Tell if a file belongs to a picture source: an image of a format the decoder can read, a
+/// manifest or a picture sidecar
+fn is_picture_source_file(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    if Manifest::is_manifest_name(&name) || Manifest::sidecar_target(&name).is_some() {
+        return true;
+    }
+    return ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled());
+}
+
 /// Load an image from the provided path
-pub fn load_image_from_disk(fname: &Path) -> DynamicImage {
-    let file = fs::File::open(fname).unwrap();
+pub fn load_image_from_disk(fname: &Path) -> Result<DynamicImage> {
+    let file = fs::File::open(fname).map_err(|e| Error::io(fname, e))?;
     let reader = BufReader::new(file);
-    let image = ImageReader::new(reader)
+    return ImageReader::new(reader)
         .with_guessed_format()
-        .unwrap()
+        .map_err(|e| Error::io(fname, e))?
         .decode()
-        .unwrap();
-    image
+        .map_err(|source| Error::Decoding {
+            name: fname.display().to_string(),
+            source,
+        });
 }
 
 /// Load bytes from a file, if it ests at this path. Return `None` otherwise.
 pub fn load_bytes_from_disk(fpath: &Path) -> Option<Vec<u8>> {
-    return match std::fs::read(fpath) {
-        Ok(bytes) => Some(bytes),
-        Err(_) => None,
-    };
+    return std::fs::read(fpath).ok();
 }
 
 /// Save provided bytes to the path specified
-pub fn save_bytes_to_disk(fpath: &Path, bytes: &Vec<u8>) {
-    let file = match std::fs::File::create(&fpath) {
-        Ok(f) => f,
-        Err(e) => panic!(
-            "Error with path {} ({e})",
-            fpath.as_os_str().to_str().unwrap()
-        ),
-    };
-    std::io::BufWriter::new(file).write_all(&bytes).unwrap();
+pub fn save_bytes_to_disk(fpath: &Path, bytes: &[u8]) -> Result<()> {
+    let file = std::fs::File::create(fpath).map_err(|e| Error::io(fpath, e))?;
+    return std::io::BufWriter::new(file)
+        .write_all(bytes)
+        .map_err(|e| Error::io(fpath, e));
 }
 
-/// Decode provided image data
-pub fn decode_image(pict_data: &Vec<u8>, name: &String) -> DynamicImage {
+/// Decode provided image data, turning it upright according to its EXIF orientation
+pub fn decode_image(pict_data: &[u8], name: &str) -> Result<DynamicImage> {
+    let decoding_error = |source| Error::Decoding {
+        name: name.to_string(),
+        source,
+    };
+
     let buff_reader = Cursor::new(pict_data);
-    let src_reader = ImageReader::new(buff_reader).with_guessed_format().unwrap();
-    return match src_reader.decode() {
-        Ok(img) => img,
-        Err(ref e) => {
-            // More explicit error message
-            panic!("An error occured when decoding {name}: {e}");
-        }
+    let mut decoder = ImageReader::new(buff_reader)
+        .with_guessed_format()
+        .map_err(|e| Error::io(name, e))?
+        .into_decoder()
+        .map_err(decoding_error)?;
+
+    // Invalid orientation metadata should not prevent using the picture
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(decoding_error)?;
+    image.apply_orientation(orientation);
+    return Ok(image);
+}
+
+/// Get the EXIF orientation of an image, without decoding its pixels
+pub fn read_orientation(pict_data: &[u8]) -> Orientation {
+    return ImageReader::new(Cursor::new(pict_data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .and_then(|mut decoder| decoder.orientation().ok())
+        .unwrap_or(Orientation::NoTransforms);
+}
+
+/// Get the (width, height) of an image once turned upright, without decoding its pixels
+pub fn upright_size(pict_data: &[u8], name: &str) -> Result<(u32, u32)> {
+    let image_size = imagesize::blob_size(pict_data).map_err(|source| Error::ImageSize {
+        name: name.to_string(),
+        source,
+    })?;
+    let (width, height) = (image_size.width as u32, image_size.height as u32);
+
+    return match read_orientation(pict_data) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => Ok((height, width)),
+        _ => Ok((width, height)),
     };
 }
 
 /// Get JPEG-encoded data for an image (which is consumed)
-pub fn encode_to_jpeg(image: DynamicImage, name: &String) -> Vec<u8> {
+pub fn encode_to_jpeg(image: DynamicImage, quality: u8, name: &str) -> Result<Vec<u8>> {
     let mut bytes: Vec<u8> = Vec::new();
-    let encoding_result = image
+    image
         .into_rgb8() // Avoid JPEG encoding error when an alpha channel is present in source image
-        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Jpeg);
-    if let Err(e) = encoding_result {
-        panic!("An error occured when encoding {name} to JPEG: {e}");
-    }
-    return bytes;
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))
+        .map_err(|source| Error::Encoding {
+            name: name.to_string(),
+            source,
+        })?;
+    return Ok(bytes);
 }
 
 /// Replace characters invalid in a (Windows) filename
-pub fn sanitize_filename(filename: &String) -> String {
-    return str::replace(filename.as_str(), '"', "_");
+pub fn sanitize_filename(filename: &str) -> String {
+    return str::replace(filename, '"', "_");
 }
 
 /// Compute a resolution in DPI (PPI actually) from a definition (pixel size) and its printed size (in cm)
@@ -117,6 +248,6 @@ pub fn compute_dpi(pixel_size: usize, cm_size: f32) -> u32 {
 }
 
 /// Compose diacritics (those are not supported by pdfium-render)
-pub fn normalize_unicode(to_normalize: &String) -> String {
+pub fn normalize_unicode(to_normalize: &str) -> String {
     return to_normalize.nfc().collect();
 }
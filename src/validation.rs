@@ -0,0 +1,182 @@
+use crate::imaging::{self, ImageJob};
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Larger images are scaled down to fit this size for analysis (faster, and metrics depend less
+/// on the image resolution)
+const ANALYSIS_SIZE: u32 = 512;
+
+/// Sharpness is measured on tiles of a grid of this size; the sharpest tile counts
+/// (the subject is usually in focus while the background is not)
+const SHARPNESS_TILES: u32 = 4;
+
+/// Thresholds under which pictures are flagged as unfit for print
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+    /// Minimum resolution of the cropped picture, once placed in its cell
+    pub min_dpi: u32,
+    /// Maximum share of the picture lost to cropping (0 to 1)
+    pub max_crop_loss: f32,
+    /// Minimum mean luminance (0 to 255)
+    pub min_brightness: f32,
+    /// Maximum share of burnt out pixels (0 to 1)
+    pub max_clipped: f32,
+    /// Minimum local sharpness (variance of the Laplacian of the sharpest area)
+    pub min_sharpness: f32,
+    /// Draw a marker on flagged pictures in the debug overlay
+    pub mark_in_overlay: bool,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        return ValidationConfig {
+            min_dpi: 150,
+            max_crop_loss: 0.35,
+            min_brightness: 45.,
+            max_clipped: 0.25,
+            min_sharpness: 25.,
+            mark_in_overlay: true,
+        };
+    }
+}
+
+/// A reason for a picture to be checked before printing
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    LowDpi { dpi: u32, min_dpi: u32 },
+    CropLoss { share: f32 },
+    TooDark { brightness: f32 },
+    Overexposed { clipped: f32 },
+    Blurry { sharpness: f32 },
+}
+
+impl Issue {
+    /// A few letters to tell issues apart on the poster itself
+    pub fn code(&self) -> &'static str {
+        return match self {
+            Issue::LowDpi { .. } => "DPI",
+            Issue::CropLoss { .. } => "CROP",
+            Issue::TooDark { .. } => "DARK",
+            Issue::Overexposed { .. } => "OVER",
+            Issue::Blurry { .. } => "BLUR",
+        };
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Issue::LowDpi { dpi, min_dpi } => {
+                write!(
+                    f,
+                    "Resolution of {dpi} DPI, below the {min_dpi} DPI minimum"
+                )
+            }
+            Issue::CropLoss { share } => {
+                write!(f, "{:.0}% of the picture is cropped out", share * 100.)
+            }
+            Issue::TooDark { brightness } => {
+                write!(f, "Very dark (mean brightness {brightness:.0})")
+            }
+            Issue::Overexposed { clipped } => {
+                write!(f, "Overexposed ({:.0}% burnt out)", clipped * 100.)
+            }
+            Issue::Blurry { sharpness } => write!(f, "Likely blurry (sharpness {sharpness:.1})"),
+        };
+    }
+}
+
+/// Check a picture placement, and the `cropped` (decoded) source image shown in its cell
+pub fn validate(job: &ImageJob, cropped: &DynamicImage, config: &ValidationConfig) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let dpi = imaging::effective_dpi(&job.crop, job.image_width);
+    if dpi < config.min_dpi {
+        issues.push(Issue::LowDpi {
+            dpi,
+            min_dpi: config.min_dpi,
+        });
+    }
+
+    let (src_width, src_height) = job.src_size;
+    let kept = (job.crop.width as f32 * job.crop.height as f32)
+        / (src_width as f32 * src_height as f32).max(1.);
+    if 1. - kept > config.max_crop_loss {
+        issues.push(Issue::CropLoss { share: 1. - kept });
+    }
+
+    let gray = analysis_image(cropped);
+
+    let brightness = mean_brightness(&gray);
+    if brightness < config.min_brightness {
+        issues.push(Issue::TooDark { brightness });
+    }
+
+    let clipped = clipped_share(&gray);
+    if clipped > config.max_clipped {
+        issues.push(Issue::Overexposed { clipped });
+    }
+
+    let sharpness = local_sharpness(&gray);
+    if sharpness < config.min_sharpness {
+        issues.push(Issue::Blurry { sharpness });
+    }
+
+    return issues;
+}
+
+fn analysis_image(image: &DynamicImage) -> GrayImage {
+    if image.width() > ANALYSIS_SIZE || image.height() > ANALYSIS_SIZE {
+        return image.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).into_luma8();
+    }
+    return image.to_luma8();
+}
+
+fn mean_brightness(gray: &GrayImage) -> f32 {
+    let count = (gray.width() * gray.height()).max(1);
+    let sum: u64 = gray.pixels().map(|p| p.0[0] as u64).sum();
+    return sum as f32 / count as f32;
+}
+
+fn clipped_share(gray: &GrayImage) -> f32 {
+    let count = (gray.width() * gray.height()).max(1);
+    let clipped = gray.pixels().filter(|p| p.0[0] >= 250).count();
+    return clipped as f32 / count as f32;
+}
+
+/// Get the highest variance of the Laplacian among image tiles
+fn local_sharpness(gray: &GrayImage) -> f32 {
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.;
+    }
+
+    let tile_width = width.div_ceil(SHARPNESS_TILES);
+    let tile_height = height.div_ceil(SHARPNESS_TILES);
+    let nb_tiles = (SHARPNESS_TILES * SHARPNESS_TILES) as usize;
+
+    // (count, sum, sum of squares) by tile
+    let mut tiles = vec![(0u32, 0f64, 0f64); nb_tiles];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let at = |x: u32, y: u32| gray.get_pixel(x, y).0[0] as f64;
+            let laplacian =
+                4. * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1);
+            let tile = &mut tiles[((y / tile_height) * SHARPNESS_TILES + x / tile_width) as usize];
+            tile.0 += 1;
+            tile.1 += laplacian;
+            tile.2 += laplacian * laplacian;
+        }
+    }
+
+    return tiles
+        .iter()
+        .filter(|(count, _, _)| *count > 0)
+        .map(|(count, sum, squares)| {
+            let mean = sum / *count as f64;
+            (squares / *count as f64 - mean * mean) as f32
+        })
+        .fold(0., f32::max);
+}